@@ -1,7 +1,11 @@
 use crate::{
 	bail_let,
 	cli::{OutputFormat, SkipPhases},
-	config::{Manifest, Script},
+	config::{
+		BootMode, Compression, Compressor, CopyStage, EfibootConfig, ExtlinuxConfig, IsoConfig,
+		KernelModulesConfig, Manifest, PartitionLayout, Script, SmokeTestConfig, UBootConfig,
+		ZramConfig,
+	},
 	env_flag,
 	util::{just_write, loopdev_with_file},
 };
@@ -19,6 +23,9 @@ use tracing::{debug, info, trace, warn};
 const WORKDIR: &str = "katsu-work";
 crate::prepend_comment!(GRUB_PREPEND_COMMENT: "/boot/grub/grub.cfg", "Grub configurations", katsu::builder::Bootloader::cp_grub);
 crate::prepend_comment!(LIMINE_PREPEND_COMMENT: "/boot/limine.cfg", "Limine configurations", katsu::builder::Bootloader::cp_limine);
+crate::prepend_comment!(SYSLINUX_PREPEND_COMMENT: "/isolinux/isolinux.cfg", "ISOLINUX configurations", katsu::builder::Bootloader::cp_syslinux);
+crate::prepend_comment!(EXTLINUX_PREPEND_COMMENT: "/boot/extlinux/extlinux.conf", "U-Boot boot menu", katsu::builder::extlinux_conf);
+crate::prepend_comment!(IPXE_PREPEND_COMMENT: "boot.ipxe", "iPXE boot script", katsu::builder::PxeBuilder::export);
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum Bootloader {
@@ -27,6 +34,8 @@ pub enum Bootloader {
 	GrubBios,
 	Limine,
 	SystemdBoot,
+	/// BIOS-only ISOLINUX, for simple live media (ISOs only)
+	Syslinux,
 }
 
 impl From<&str> for Bootloader {
@@ -36,6 +45,7 @@ impl From<&str> for Bootloader {
 			"grub" | "grub2" => Self::Grub,
 			"grub-bios" => Self::GrubBios,
 			"systemd-boot" => Self::SystemdBoot,
+			"syslinux" | "isolinux" | "extlinux" => Self::Syslinux,
 			_ => {
 				warn!("Unknown bootloader: {value}, falling back to GRUB");
 				Self::Grub
@@ -44,7 +54,21 @@ impl From<&str> for Bootloader {
 	}
 }
 
+/// What a bootloader needs to generate the boot files, see [`Bootloader::requirements`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootloaderRequirements {
+	/// Binaries that must be in the host's `PATH`
+	pub host_bins: Vec<&'static str>,
+	/// Files copied from the host
+	pub host_files: Vec<PathBuf>,
+	/// Paths relative to the chroot
+	pub chroot_paths: Vec<String>,
+	/// EFI binaries in the chroot's EFI directory (see [`efi_dir`])
+	pub efi_bins: Vec<String>,
+}
+
 impl Bootloader {
+	#[tracing::instrument(target = "katsu::bootloader", skip(self), fields(bootloader = ?self))]
 	pub fn install(&self, image: &Path) -> Result<()> {
 		match *self {
 			Self::Grub => info!("GRUB is not required to be installed to image, skipping"),
@@ -53,6 +77,8 @@ impl Bootloader {
 			Self::GrubBios => {
 				cmd_lib::run_cmd!(grub-install --target=i386-pc --boot-directory=$image/boot 2>&1)?
 			},
+			// make the ISO bootable from USB drives too
+			Self::Syslinux => cmd_lib::run_cmd!(isohybrid $image 2>&1)?,
 		}
 		Ok(())
 	}
@@ -60,46 +86,104 @@ impl Bootloader {
 		match *self {
 			Self::Grub => ("boot/efi/EFI/fedora/shim.efi", "boot/eltorito.img"),
 			Self::Limine => ("boot/limine-uefi-cd.bin", "boot/limine-bios-cd.bin"),
+			// BIOS only, there is no UEFI image
+			Self::Syslinux => ("", "isolinux/isolinux.bin"),
 			Self::GrubBios => todo!(),
 			Self::SystemdBoot => todo!(),
 		}
 	}
-	fn cp_vmlinuz_initramfs(&self, chroot: &Path, dest: &Path) -> Result<(String, String)> {
-		trace!("Finding vmlinuz and initramfs");
-		let bootdir = chroot.join("boot");
-		let mut vmlinuz = None;
-		let mut initramfs = None;
-		for f in bootdir.read_dir()? {
-			let f = f?;
-			if !f.metadata()?.is_file() {
-				continue;
-			}
-			let name = f.file_name();
-			debug!(?name, "File in /boot");
-			let name = name.to_string_lossy();
-			if name.contains("-rescue-") {
-				continue;
-			}
+	/// Everything needed on the host and in the chroot to generate the boot files for `arch`
+	pub fn requirements(&self, arch: &str, iso: &IsoConfig) -> Result<BootloaderRequirements> {
+		let mut req = BootloaderRequirements::default();
+		match *self {
+			Self::Grub => {
+				let target = match arch {
+					"x86_64" => "i386-pc",
+					"aarch64" => "arm64-efi",
+					_ => bail!("GRUB does not support building for {arch}"),
+				};
+				req.host_bins = vec!["grub2-mkimage", "grub2-mkrescue", "mkfs.fat", "xorrisofs"];
+				req.chroot_paths.push(format!("usr/lib/grub/{target}"));
+				if arch == "x86_64" && !iso.gpt_only {
+					req.chroot_paths.push("usr/lib/grub/i386-pc/boot_hybrid.img".to_string());
+				}
+				req.efi_bins = vec![iso.shim_name(arch)?, iso.grub_name(arch)?];
+			},
+			Self::GrubBios => {
+				req.host_bins = vec!["grub2-install"];
+				req.chroot_paths.push("usr/lib/grub/i386-pc".to_string());
+			},
+			Self::Limine => {
+				req.host_bins = vec!["limine", "b2sum", "xorriso"];
+				req.host_files =
+					LIMINE_FILES.iter().map(|f| Path::new(LIMINE_DIR).join(f)).collect();
+			},
+			Self::SystemdBoot => req.host_bins = vec!["bootctl"],
+			Self::Syslinux => {
+				if arch != "x86_64" {
+					bail!("Syslinux does not support building for {arch}");
+				}
+				req.host_bins = vec!["xorriso", "isohybrid"];
+				req.chroot_paths =
+					SYSLINUX_FILES.iter().map(|f| format!("{SYSLINUX_DIR}/{f}")).collect();
+			},
+		}
+		Ok(req)
+	}
 
-			if name.starts_with("vmlinuz-") {
-				vmlinuz = Some(name.to_string());
-			} else if name.starts_with("initramfs-") {
-				initramfs = Some(name.to_string());
-			}
-			if vmlinuz.is_some() && initramfs.is_some() {
-				break;
-			}
+	/// Checks that the host has the binaries and files needed for this bootloader
+	pub fn check_host(&self, arch: &str, iso: &IsoConfig) -> Result<()> {
+		let req = self.requirements(arch, iso)?;
+		let missing: Vec<_> = req
+			.host_bins
+			.iter()
+			.filter(|b| crate::util::find_in_path(b).is_none())
+			.map(|b| b.to_string())
+			.chain(req.host_files.iter().filter(|f| !f.exists()).map(|f| f.display().to_string()))
+			.collect();
+		if !missing.is_empty() {
+			bail!("Missing host binaries or files required by {self:?}: {}", missing.join(", "));
 		}
+		Ok(())
+	}
 
-		bail_let!(Some(vmlinuz) = vmlinuz => "Cannot find vmlinuz in {bootdir:?}");
-		bail_let!(Some(initramfs) = initramfs => "Cannot find initramfs in {bootdir:?}");
+	/// Checks that the chroot has the files needed for this bootloader on `arch`
+	pub fn check_chroot(&self, chroot: &Path, arch: &str, iso: &IsoConfig) -> Result<()> {
+		let req = self.requirements(arch, iso)?;
+		let efi_dir = efi_dir(chroot);
+		let efi_dir = efi_dir.strip_prefix(chroot).unwrap_or(&efi_dir);
+		let missing: Vec<_> = req
+			.chroot_paths
+			.into_iter()
+			.chain(req.efi_bins.into_iter().map(|b| efi_dir.join(b).display().to_string()))
+			.filter(|p| !chroot.join(p).exists())
+			.collect();
+		if !missing.is_empty() {
+			bail!(
+				"Missing files in chroot required by {self:?} for {arch}: {}. Are the bootloader packages for {arch} installed?",
+				missing.join(", ")
+			);
+		}
+		Ok(())
+	}
 
-		trace!(vmlinuz, initramfs, "Copying vmlinuz and initramfs");
+	/// Copies the kernel and the initrd images to `dest`, returning their file names with the
+	/// initrds in load order
+	fn cp_vmlinuz_initramfs(
+		&self, manifest: &Manifest, chroot: &Path, dest: &Path,
+	) -> Result<(String, Vec<String>)> {
+		let bootdir = chroot.join("boot");
+		let (vmlinuz, initramfs) = find_vmlinuz_initramfs(chroot)?;
+		let initrds = manifest.iso.clone().unwrap_or_default().initrds(&initramfs);
+
+		trace!(vmlinuz, ?initrds, "Copying vmlinuz and initrds");
 		std::fs::create_dir_all(dest.join("boot"))?;
 		std::fs::copy(bootdir.join(&vmlinuz), dest.join("boot").join(&vmlinuz))?;
-		std::fs::copy(bootdir.join(&initramfs), dest.join("boot").join(&initramfs))?;
+		for initrd in &initrds {
+			std::fs::copy(bootdir.join(initrd), dest.join("boot").join(initrd))?;
+		}
 
-		Ok((vmlinuz, initramfs))
+		Ok((vmlinuz, initrds))
 	}
 
 	fn cp_limine(&self, manifest: &Manifest, chroot: &Path) -> Result<()> {
@@ -110,22 +194,17 @@ impl Bootloader {
 		let root = chroot.parent().unwrap().join(ISO_TREE);
 		// std::fs::create_dir_all(format!("./{distro}/LiveOS"))?;
 		std::fs::create_dir_all(root.join("boot"))?;
-		std::fs::copy(
-			"/usr/share/limine/limine-uefi-cd.bin",
-			root.join("boot/limine-uefi-cd.bin"),
-		)?;
-		std::fs::copy(
-			"/usr/share/limine/limine-bios-cd.bin",
-			root.join("boot/limine-bios-cd.bin"),
-		)?;
-		std::fs::copy("/usr/share/limine/limine-bios.sys", root.join("boot/limine-bios.sys"))?;
+		for f in LIMINE_FILES {
+			std::fs::copy(Path::new(LIMINE_DIR).join(f), root.join("boot").join(f))?;
+		}
 
-		let (vmlinuz, initramfs) = self.cp_vmlinuz_initramfs(chroot, &root)?;
+		let (vmlinuz, initrds) = self.cp_vmlinuz_initramfs(manifest, chroot, &root)?;
 		let volid = manifest.get_volid();
+		let live_args = manifest.get_live_args();
 
 		// Generate limine.cfg
 		let limine_cfg = root.join("boot/limine.cfg");
-		crate::tpl!("limine.cfg.tera" => { LIMINE_PREPEND_COMMENT, distro, vmlinuz, initramfs, cmd, volid } => &limine_cfg);
+		crate::tpl!("limine.cfg.tera" => { LIMINE_PREPEND_COMMENT, distro, vmlinuz, initrds, cmd, volid, live_args } => &limine_cfg);
 
 		let binding = run_fun!(b2sum $limine_cfg)?;
 		let liminecfg_b2h = binding.split_whitespace().next().unwrap();
@@ -140,24 +219,66 @@ impl Bootloader {
 
 		Ok(())
 	}
+	/// Renders `isolinux.cfg` for the live media
+	fn syslinux_cfg(manifest: &Manifest, vmlinuz: &str, initrds: &[String]) -> Result<String> {
+		let cmd = manifest.get_cmdline(BootMode::Bios);
+		let volid = manifest.get_volid();
+		let live_args = manifest.get_live_args();
+		let distro = &manifest.distro.as_ref().map_or("Linux", |s| s);
+
+		Ok(
+			crate::tpl!("isolinux.cfg.tera" => { SYSLINUX_PREPEND_COMMENT, volid, distro, vmlinuz, initrds, cmd, live_args }),
+		)
+	}
+
+	fn cp_syslinux(&self, manifest: &Manifest, chroot: &Path) -> Result<()> {
+		info!("Copying ISOLINUX files");
+		let root = chroot.parent().unwrap().join(ISO_TREE);
+		let isolinux = root.join("isolinux");
+		std::fs::create_dir_all(&isolinux)?;
+		for f in SYSLINUX_FILES {
+			std::fs::copy(chroot.join(SYSLINUX_DIR).join(f), isolinux.join(f))?;
+		}
+
+		let (vmlinuz, initrds) = self.cp_vmlinuz_initramfs(manifest, chroot, &root)?;
+		crate::util::just_write(
+			isolinux.join("isolinux.cfg"),
+			Self::syslinux_cfg(manifest, &vmlinuz, &initrds)?,
+		)
+	}
+
+	/// Builds the `mkfs.fat` command formatting the EFI image on `dev`
+	fn mkfs_efiboot_cmd(efiboot: &EfibootConfig, dev: &Path) -> std::process::Command {
+		let mut cmd = std::process::Command::new("mkfs.fat");
+		if let Some(fat) = efiboot.fat {
+			cmd.arg("-F").arg(fat.to_string());
+		}
+		cmd.arg("-v").arg("-n").arg(efiboot.get_label()).arg(dev);
+		cmd
+	}
+
 	/// A clone of mkefiboot from lorax
 	/// Currently only works for PC, no mac support
-	fn mkefiboot(&self, chroot: &Path, _: &Manifest) -> Result<()> {
+	fn mkefiboot(&self, chroot: &Path, manifest: &Manifest) -> Result<()> {
 		let tree = chroot.parent().unwrap().join(ISO_TREE);
+		let efiboot = manifest.iso.as_ref().map(|iso| iso.efiboot.clone()).unwrap_or_default();
 
 		// TODO: Add mac boot support
 
 		// make EFI disk
 		let sparse_path = &tree.join("boot/efiboot.img");
-		crate::util::create_sparse(sparse_path, 25 * 1024 * 1024)?; // 15MiB
+		crate::util::create_sparse(sparse_path, efiboot.get_size().as_u64())?;
 
 		// let's mount the disk as a loop device
 		let (ldp, hdl) = loopdev_with_file(sparse_path)?;
 
-		cmd_lib::run_cmd!(
-			// Format disk with mkfs.fat
-			mkfs.msdos $ldp -v -n EFI 2>&1;
+		// Format disk with mkfs.fat
+		let status = Self::mkfs_efiboot_cmd(&efiboot, &ldp).status()?;
+		if !status.success() {
+			bail!("Failed to format EFI image: mkfs.fat returned {status}");
+		}
 
+		cmd_lib::run_cmd!(
 			// Mount disk to /tmp/katsu.efiboot
 			mkdir -p /tmp/katsu.efiboot;
 			mount $ldp /tmp/katsu.efiboot;
@@ -172,28 +293,74 @@ impl Bootloader {
 		Ok(())
 	}
 
-	fn cp_grub(&self, manifest: &Manifest, chroot: &Path) -> Result<()> {
-		let imgd = chroot.parent().unwrap().join(ISO_TREE);
-		let cmd = &manifest.kernel_cmdline.as_ref().map_or("", |s| s);
+	/// Renders the GRUB config for the live media
+	fn grub_cfg(
+		&self, manifest: &Manifest, vmlinuz: &str, initrds: &[String], mode: BootMode,
+	) -> Result<String> {
+		let cmd = manifest.get_cmdline(mode);
 		let volid = manifest.get_volid();
+		let live_args = manifest.get_live_args();
+		let distro = &manifest.distro.as_ref().map_or("Linux", |s| s);
+		let iso = manifest.iso.clone().unwrap_or_default();
+		let timeout = iso.grub_timeout.unwrap_or(60);
+		let timeout_style = iso.grub_timeout_style;
+		let bls = iso.bls;
+		let configfile = iso.grub_configfile.as_ref().map(|p| Path::new("/").join(p));
+		let rootfs = manifest.get_rootfs_path();
+
+		Ok(
+			crate::tpl!("grub.cfg.tera" => { GRUB_PREPEND_COMMENT, volid, distro, vmlinuz, initrds, cmd, live_args, timeout, timeout_style, bls, configfile, rootfs }),
+		)
+	}
 
-		let (vmlinuz, initramfs) = self.cp_vmlinuz_initramfs(chroot, &imgd)?;
+	/// Sets up `boot/grub` in the ISO tree after `/boot` is copied over.
+	///
+	/// Minimal chroots may not have a populated `/boot/grub2` or `/boot/grub`, in which case
+	/// the modules from `/usr/lib/grub` and fonts from `/usr/share/grub` are used instead.
+	fn cp_grub_dir(chroot: &Path, imgd: &Path) -> Result<()> {
+		let grub = imgd.join("boot/grub");
+		if imgd.join("boot/grub2").exists() {
+			std::fs::rename(imgd.join("boot/grub2"), &grub)?;
+			return Ok(());
+		}
+		if grub.exists() {
+			return Ok(());
+		}
 
-		let _ = std::fs::remove_dir_all(imgd.join("boot"));
-		cmd_lib::run_cmd!(cp -r $chroot/boot $imgd/)?;
-		std::fs::rename(imgd.join("boot/grub2"), imgd.join("boot/grub"))?;
+		let modules = chroot.join("usr/lib/grub");
+		if !modules.exists() {
+			bail!("Cannot find GRUB files in /boot/grub2, /boot/grub or /usr/lib/grub");
+		}
+		warn!("No GRUB directory in /boot, falling back to /usr/lib/grub");
+		std::fs::create_dir_all(grub.join("fonts"))?;
+		cmd_lib::run_cmd!(cp -r $modules/. $grub 2>&1)?;
+
+		let fonts = chroot.join("usr/share/grub");
+		for font in fonts.read_dir().into_iter().flatten() {
+			let font = font?.path();
+			if font.extension().is_some_and(|ext| ext == "pf2") {
+				std::fs::copy(&font, grub.join("fonts").join(font.file_name().unwrap()))?;
+			}
+		}
+		Ok(())
+	}
 
-		let distro = &manifest.distro.as_ref().map_or("Linux", |s| s);
+	fn cp_grub(&self, manifest: &Manifest, chroot: &Path) -> Result<()> {
+		let imgd = chroot.parent().unwrap().join(ISO_TREE);
 
-		crate::tpl!("grub.cfg.tera" => { GRUB_PREPEND_COMMENT, volid, distro, vmlinuz, initramfs, cmd } => imgd.join("boot/grub/grub.cfg"));
+		let (vmlinuz, initrds) = self.cp_vmlinuz_initramfs(manifest, chroot, &imgd)?;
 
-		let arch_short = match manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH) {
-			"x86_64" => "x64",
-			"aarch64" => "aa64",
-			_ => unimplemented!(),
-		};
+		let _ = std::fs::remove_dir_all(imgd.join("boot"));
+		cmd_lib::run_cmd!(cp -r $chroot/boot $imgd/)?;
+		if manifest.iso.as_ref().is_some_and(|iso| iso.bls) {
+			cp_bls_entries(chroot, &imgd, &live_bls_options(manifest))?;
+		}
+		Self::cp_grub_dir(chroot, &imgd)?;
 
-		let arch_short_upper = arch_short.to_uppercase();
+		// BIOS boots through eltorito.img which reads /boot/grub, UEFI reads EFI/BOOT
+		let bios_cfg = self.grub_cfg(manifest, &vmlinuz, &initrds, BootMode::Bios)?;
+		let uefi_cfg = self.grub_cfg(manifest, &vmlinuz, &initrds, BootMode::Uefi)?;
+		just_write(imgd.join("boot/grub/grub.cfg"), bios_cfg)?;
 
 		let arch_32 = match manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH) {
 			"x86_64" => "ia32",
@@ -204,14 +371,24 @@ impl Bootloader {
 
 		// Funny script to install GRUB
 		let _ = std::fs::create_dir_all(imgd.join("EFI/BOOT/fonts"));
-		cmd_lib::run_cmd!(
-			cp -av $imgd/boot/efi/EFI/fedora/. $imgd/EFI/BOOT;
-			cp -av $imgd/boot/grub/grub.cfg $imgd/EFI/BOOT/BOOT.conf 2>&1;
-			cp -av $imgd/boot/grub/grub.cfg $imgd/EFI/BOOT/grub.cfg 2>&1;
-			cp -av $imgd/boot/grub/fonts/unicode.pf2 $imgd/EFI/BOOT/fonts;
-			cp -av $imgd/EFI/BOOT/shim${arch_short}.efi $imgd/EFI/BOOT/BOOT${arch_short_upper}.efi;
-			cp -av $imgd/EFI/BOOT/shim.efi $imgd/EFI/BOOT/BOOT${arch_32}.efi;
-		)?;
+		cp_efi_files(chroot, &imgd)?;
+		let arch = manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+		let iso = manifest.iso.clone().unwrap_or_default();
+		let grub_efi = imgd.join("EFI/BOOT").join(iso.grub_name(arch)?);
+		if iso.grub_mkimage || !grub_efi.exists() {
+			info!(?grub_efi, "Generating GRUB EFI image");
+			let status = grub_efi_mkimage_cmd(chroot, arch, &grub_efi)?.status()?;
+			if !status.success() {
+				bail!("grub2-mkimage failed with {status}");
+			}
+		}
+		cp_efi_boot_bins(&imgd, arch, &iso)?;
+		cmd_lib::run_cmd!(cp -av $imgd/boot/grub/fonts/unicode.pf2 $imgd/EFI/BOOT/fonts)?;
+		if imgd.join("EFI/BOOT/shim.efi").exists() {
+			cmd_lib::run_cmd!(cp -av $imgd/EFI/BOOT/shim.efi $imgd/EFI/BOOT/BOOT${arch_32}.efi)?;
+		}
+		just_write(imgd.join("EFI/BOOT/BOOT.conf"), &uefi_cfg)?;
+		just_write(imgd.join("EFI/BOOT/grub.cfg"), &uefi_cfg)?;
 
 		// and then we need to generate eltorito.img
 		let host_arch = std::env::consts::ARCH;
@@ -262,6 +439,7 @@ impl Bootloader {
 		Ok(())
 	}
 
+	#[tracing::instrument(target = "katsu::bootloader", skip_all, fields(bootloader = ?self))]
 	pub fn copy_liveos(&self, manifest: &Manifest, chroot: &Path) -> Result<()> {
 		info!("Copying bootloader files");
 		match *self {
@@ -269,6 +447,7 @@ impl Bootloader {
 			Self::Limine => self.cp_limine(manifest, chroot)?,
 			Self::SystemdBoot => todo!(),
 			Self::GrubBios => self.cp_grub_bios(chroot)?,
+			Self::Syslinux => self.cp_syslinux(manifest, chroot)?,
 		}
 		Ok(())
 	}
@@ -278,6 +457,138 @@ impl Bootloader {
 	}
 }
 
+/// Where the EFI binaries (shim, GRUB) are found in the chroot
+///
+/// Images managed by bootupd (bootc/ostree) ship them in `/usr/lib/bootupd/updates` instead of
+/// installing them to the ESP.
+pub fn efi_dir(chroot: &Path) -> PathBuf {
+	let updates = chroot.join("usr/lib/bootupd/updates/EFI");
+	if chroot.join("usr/bin/bootupctl").exists() && updates.is_dir() {
+		let vendor = updates
+			.read_dir()
+			.into_iter()
+			.flatten()
+			.flatten()
+			.map(|e| e.path())
+			.find(|p| p.is_dir() && !p.ends_with("BOOT"));
+		if let Some(vendor) = vendor {
+			debug!(?vendor, "Using EFI files from bootupd");
+			return vendor;
+		}
+		warn!(?updates, "bootupd is installed but no EFI vendor directory was found");
+	}
+	chroot.join("boot/efi/EFI/fedora")
+}
+
+/// Copies the EFI binaries from the chroot to `EFI/BOOT` in the ISO tree
+fn cp_efi_files(chroot: &Path, imgd: &Path) -> Result<()> {
+	let efi_dir = efi_dir(chroot);
+	let dest = imgd.join("EFI/BOOT");
+	fs::create_dir_all(&dest)?;
+	if !efi_dir.is_dir() {
+		warn!(?efi_dir, "No EFI binaries in the chroot");
+		return Ok(());
+	}
+	cmd_lib::run_cmd!(cp -av $efi_dir/. $dest 2>&1)?;
+	Ok(())
+}
+
+/// GRUB modules built into the generated EFI image, enough to find and read the ISO's
+/// `EFI/BOOT/grub.cfg` and boot Linux from it
+const GRUB_EFI_MODULES: &[&str] = &[
+	"all_video",
+	"chain",
+	"configfile",
+	"echo",
+	"efi_gop",
+	"ext2",
+	"fat",
+	"gzio",
+	"iso9660",
+	"linux",
+	"normal",
+	"part_gpt",
+	"part_msdos",
+	"regexp",
+	"search",
+	"search_fs_uuid",
+	"search_label",
+	"test",
+];
+
+/// Builds the `grub2-mkimage` command generating the GRUB EFI binary `out` for `arch` from the
+/// chroot's GRUB modules, for chroots without a prebuilt one
+fn grub_efi_mkimage_cmd(chroot: &Path, arch: &str, out: &Path) -> Result<std::process::Command> {
+	let format = match arch {
+		"x86_64" => "x86_64-efi",
+		"aarch64" => "arm64-efi",
+		_ => bail!("Cannot generate a GRUB EFI image for {arch}"),
+	};
+	let mut cmd = std::process::Command::new("grub2-mkimage");
+	cmd.arg("-O").arg(format).arg("-d").arg(chroot.join("usr/lib/grub").join(format));
+	cmd.arg("-o").arg(out).arg("-p").arg("/EFI/BOOT").args(GRUB_EFI_MODULES);
+	if arch == "x86_64" {
+		cmd.arg("efi_uga");
+	}
+	Ok(cmd)
+}
+
+/// Sets up the fallback boot path in the ISO tree's `EFI/BOOT`, after [`cp_efi_files`]
+///
+/// shim becomes `BOOT<arch>.efi`, and GRUB is copied to the name shim loads if it has a
+/// different name. Without shim, GRUB itself becomes `BOOT<arch>.efi`.
+fn cp_efi_boot_bins(imgd: &Path, arch: &str, iso: &IsoConfig) -> Result<()> {
+	let efi_arch = crate::config::efi_arch(arch)?;
+	let boot = imgd.join("EFI/BOOT");
+	let (shim, grub) = (iso.shim_name(arch)?, iso.grub_name(arch)?);
+	let boot_efi = format!("BOOT{}.efi", efi_arch.to_uppercase());
+	let shim_grub = format!("grub{efi_arch}.efi");
+	if !boot.join(&shim).exists() {
+		warn!(?shim, "No shim, booting GRUB directly without Secure Boot support");
+		cmd_lib::run_cmd!(cp -av $boot/$grub $boot/$boot_efi 2>&1)?;
+		return Ok(());
+	}
+	cmd_lib::run_cmd!(cp -av $boot/$shim $boot/$boot_efi 2>&1)?;
+	if grub != shim_grub {
+		cmd_lib::run_cmd!(cp -av $boot/$grub $boot/$shim_grub 2>&1)?;
+	}
+	Ok(())
+}
+
+/// Finds the file names of the kernel and initramfs in the chroot's `/boot`
+fn find_vmlinuz_initramfs(chroot: &Path) -> Result<(String, String)> {
+	trace!("Finding vmlinuz and initramfs");
+	let bootdir = chroot.join("boot");
+	let mut vmlinuz = None;
+	let mut initramfs = None;
+	for f in bootdir.read_dir()? {
+		let f = f?;
+		if !f.metadata()?.is_file() {
+			continue;
+		}
+		let name = f.file_name();
+		debug!(?name, "File in /boot");
+		let name = name.to_string_lossy();
+		if name.contains("-rescue-") {
+			continue;
+		}
+
+		if name.starts_with("vmlinuz-") {
+			vmlinuz = Some(name.to_string());
+		} else if name.starts_with("initramfs-") {
+			initramfs = Some(name.to_string());
+		}
+		if vmlinuz.is_some() && initramfs.is_some() {
+			break;
+		}
+	}
+
+	bail_let!(Some(vmlinuz) = vmlinuz => "Cannot find vmlinuz in {bootdir:?}");
+	bail_let!(Some(initramfs) = initramfs => "Cannot find initramfs in {bootdir:?}");
+
+	Ok((vmlinuz, initramfs))
+}
+
 pub trait RootBuilder {
 	fn build(&self, chroot: &Path, manifest: &Manifest) -> Result<()>;
 }
@@ -308,20 +619,136 @@ pub struct DnfRootBuilder {
 	pub repodir: Option<PathBuf>,
 	#[serde(default)]
 	pub global_options: Vec<String>,
+	/// Koji tags to enable as extra repos during the transaction, e.g. the side tag of a
+	/// Bodhi update (`f40-build-side-12345`). They are not kept in the image.
+	#[serde(default)]
+	pub koji_tags: Vec<String>,
+	/// Koji package server for `koji_tags`, defaults to Fedora's
+	#[serde(default)]
+	pub koji_url: Option<String>,
+	/// Directory of RPMs to install from instead of the configured repos, for air-gapped
+	/// builds. Repo metadata is generated with `createrepo_c` and every other repo is disabled.
+	#[serde(default)]
+	pub offline_repo: Option<PathBuf>,
+}
+
+const DEFAULT_KOJI_URL: &str = "https://kojipkgs.fedoraproject.org";
+
+impl DnfRootBuilder {
+	/// dnf options enabling [`DnfRootBuilder::koji_tags`] for `arch`
+	///
+	/// Koji repos are unsigned, so GPG checks are turned off for them.
+	fn koji_repo_options(&self, arch: &str) -> Vec<String> {
+		let koji_url = self.koji_url.as_deref().unwrap_or(DEFAULT_KOJI_URL).trim_end_matches('/');
+		self.koji_tags
+			.iter()
+			.flat_map(|tag| {
+				let id = format!("katsu-koji-{tag}");
+				[
+					format!("--repofrompath={id},{koji_url}/repos/{tag}/latest/{arch}/"),
+					format!("--setopt={id}.gpgcheck=0"),
+				]
+			})
+			.collect()
+	}
+
+	/// `createrepo_c` command generating (or updating) the metadata of an offline repo
+	fn createrepo_cmd(dir: &Path) -> std::process::Command {
+		let mut cmd = std::process::Command::new("createrepo_c");
+		cmd.arg("--update").arg(dir);
+		cmd
+	}
+
+	/// dnf options installing exclusively from the offline repo at `dir`
+	///
+	/// Local RPMs are usually unsigned, so GPG checks are turned off like for Koji repos.
+	fn offline_repo_options(dir: &Path) -> Vec<String> {
+		let dir = dir.display();
+		vec![
+			"--disablerepo=*".to_string(),
+			format!("--repofrompath={OFFLINE_REPO_ID},{dir}"),
+			format!("--enablerepo={OFFLINE_REPO_ID}"),
+			format!("--setopt={OFFLINE_REPO_ID}.gpgcheck=0"),
+		]
+	}
 }
 
+const OFFLINE_REPO_ID: &str = "katsu-offline";
+
+/// Steps of [`DnfRootBuilder::build`], in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RootStep {
+	PreScripts,
+	CopyFiles(CopyStage),
+	Install,
+	Configure,
+	PostScripts,
+	Depmod,
+	StripRpmdb,
+}
+
+const ROOT_STEPS: &[RootStep] = &[
+	RootStep::PreScripts,
+	RootStep::CopyFiles(CopyStage::Pre),
+	RootStep::Install,
+	RootStep::CopyFiles(CopyStage::AfterInstall),
+	RootStep::Configure,
+	RootStep::PostScripts,
+	RootStep::CopyFiles(CopyStage::Post),
+	RootStep::Depmod,
+	RootStep::StripRpmdb,
+];
+
 impl RootBuilder for DnfRootBuilder {
+	#[tracing::instrument(target = "katsu::dnf", skip_all, fields(?chroot))]
 	fn build(&self, chroot: &Path, manifest: &Manifest) -> Result<()> {
-		info!("Running Pre-install scripts");
-
-		run_all_scripts(&manifest.scripts.pre, chroot, false)?;
+		for step in ROOT_STEPS {
+			trace!(?step, "Root build step");
+			match step {
+				RootStep::PreScripts => {
+					info!("Running Pre-install scripts");
+					run_all_scripts(&manifest.scripts.pre, chroot, false)?;
+				},
+				RootStep::CopyFiles(stage) => manifest.copy_files_at(chroot, *stage)?,
+				RootStep::Install => self.install(chroot, manifest)?,
+				RootStep::Configure => Self::configure(&chroot.canonicalize()?, manifest)?,
+				RootStep::PostScripts => {
+					// now, let's run some funny post-install scripts
+					info!("Running post-install scripts");
+					run_all_scripts(&manifest.scripts.post, &chroot.canonicalize()?, true)?;
+				},
+				RootStep::Depmod if manifest.kernel_modules.depmod_after_scripts => {
+					info!("Regenerating module dependencies");
+					for kver in KernelModulesConfig::kernel_versions(chroot)? {
+						KernelModulesConfig::depmod(chroot, &kver)?;
+					}
+				},
+				RootStep::Depmod => {},
+				RootStep::StripRpmdb if manifest.strip_rpmdb => strip_rpmdb(chroot, rpm_packages)?,
+				RootStep::StripRpmdb => {},
+			}
+		}
+		Ok(())
+	}
+}
 
+impl DnfRootBuilder {
+	/// Install the packages into `chroot` with dnf
+	fn install(&self, chroot: &Path, manifest: &Manifest) -> Result<()> {
 		// todo: generate different kind of fstab for iso and other builds
 		if let Some(disk) = &manifest.disk {
 			// write fstab to chroot
 			crate::util::just_write(chroot.join("etc/fstab"), disk.fstab(chroot)?)?;
+
+			// picked up by kernel-install when the kernel package is installed
+			if let Some(cmdline) = &manifest.kernel_cmdline {
+				crate::util::just_write(chroot.join("etc/kernel/cmdline"), format!("{cmdline}\n"))?;
+			}
 		}
 
+		// before the kernel is installed, so its initramfs is generated with the drop-in
+		manifest.dracut.write_conf(chroot)?;
+
 		let mut packages = self.packages.clone();
 		let mut options = self.options.clone();
 		let mut exclude = self.exclude.clone();
@@ -350,10 +777,32 @@ impl RootBuilder for DnfRootBuilder {
 			packages.append(&mut pkg.clone());
 		}
 
+		if manifest.zram.is_some() && !packages.iter().any(|p| p == ZramConfig::PACKAGE) {
+			packages.push(ZramConfig::PACKAGE.to_string());
+		}
+
 		if let Some(pkg) = self.arch_exclude.get(arch_string) {
 			exclude.append(&mut pkg.clone());
 		}
 
+		if let Some(dir) = &self.offline_repo {
+			if !self.koji_tags.is_empty() {
+				bail!("Koji repos can't be used in an offline build");
+			}
+			let dir = dir.canonicalize()?;
+			info!(?dir, "Generating offline repo metadata");
+			let status = Self::createrepo_cmd(&dir).status()?;
+			if !status.success() {
+				bail!("createrepo_c failed for {}", dir.display());
+			}
+			options.append(&mut Self::offline_repo_options(&dir));
+		}
+
+		if !self.koji_tags.is_empty() {
+			info!(tags = ?self.koji_tags, "Enabling Koji repos");
+			options.append(&mut self.koji_repo_options(arch_string));
+		}
+
 		let dnf = &self.exec;
 
 		options.append(&mut exclude.iter().map(|p| format!("--exclude={p}")).collect());
@@ -364,46 +813,188 @@ impl RootBuilder for DnfRootBuilder {
 			$dnf clean all --installroot=$chroot;
 		)?;
 
+		manifest.kernel_modules.apply(&chroot)?;
+		Ok(())
+	}
+
+	/// Set up users and system configuration after the packages are installed
+	fn configure(chroot: &Path, manifest: &Manifest) -> Result<()> {
+		let (sysusers, tmpfiles) = systemd_setup_cmds(chroot, manifest);
+		if let Some(mut cmd) = sysusers {
+			info!("Creating system users with systemd-sysusers");
+			let status = cmd.status()?;
+			if !status.success() {
+				bail!("systemd-sysusers failed with {status}");
+			}
+		}
+		if let Some(mut cmd) = tmpfiles {
+			info!("Creating files with systemd-tmpfiles");
+			let status = cmd.status()?;
+			// Some entries can't apply outside of a booted system (e.g. in /proc or /sys),
+			// which it reports as a failure
+			if !status.success() {
+				warn!(%status, "systemd-tmpfiles did not apply every entry");
+			}
+		}
+
 		info!("Setting up users");
 
 		if manifest.users.is_empty() {
 			warn!("No users specified, no users will be created!");
 		} else {
-			manifest.users.iter().try_for_each(|user| user.add_to_chroot(&chroot))?;
+			manifest.users.iter().try_for_each(|user| user.add_to_chroot(chroot))?;
+		}
+
+		manifest.write_keymap(chroot)?;
+		manifest.write_hosts(chroot)?;
+		manifest.write_tmpfs(chroot)?;
+		if let Some(seed) = &manifest.random_seed {
+			seed.write(chroot)?;
+		}
+		manifest.write_embedded(chroot)?;
+
+		if let Some(zram) = &manifest.zram {
+			info!("Configuring swap on zram");
+			just_write(chroot.join("etc/systemd/zram-generator.conf"), zram.config()?)?;
 		}
 
 		if manifest.bootloader == Bootloader::GrubBios || manifest.bootloader == Bootloader::Grub {
 			info!("Attempting to run grub2-mkconfig");
-			// crate::chroot_run_cmd!(&chroot,
-			// 	echo "GRUB_DISABLE_OS_PROBER=true" > /etc/default/grub;
-			// )?;
+			grub_mkconfig(chroot);
+		}
+		Ok(())
+	}
+}
 
-			// While grub2-mkconfig may not return 0 it should still work
-			// todo: figure out why it still wouldn't write the file to /boot/grub2/grub.cfg
-			//       but works when run inside a post script
-			let res = crate::util::enter_chroot_run(&chroot, || {
-				std::process::Command::new("grub2-mkconfig")
-					.arg("-o")
-					.arg("/boot/grub2/grub.cfg")
-					.status()?;
-				Ok(())
-			});
+/// Builds the `systemd-sysusers` and `systemd-tmpfiles` commands run against the chroot, if
+/// enabled with [`Manifest::sysusers`] and [`Manifest::tmpfiles`]
+fn systemd_setup_cmds(
+	chroot: &Path, manifest: &Manifest,
+) -> (Option<std::process::Command>, Option<std::process::Command>) {
+	let sysusers = manifest.sysusers.then(|| {
+		let mut cmd = std::process::Command::new("systemd-sysusers");
+		cmd.arg("--root").arg(chroot);
+		cmd
+	});
+	let tmpfiles = manifest.tmpfiles.then(|| {
+		let mut cmd = std::process::Command::new("systemd-tmpfiles");
+		cmd.arg("--create").arg("--root").arg(chroot);
+		cmd
+	});
+	(sysusers, tmpfiles)
+}
 
-			if let Err(e) = res {
-				warn!(?e, "grub2-mkconfig not returning 0, continuing anyway");
-			}
+/// Where the list of installed packages is written when the RPM database is stripped
+const PACKAGE_LIST: &str = "usr/lib/katsu/packages.txt";
+/// Locations of the RPM database, the old one and the one since Fedora 36
+const RPMDB_DIRS: &[&str] = &["var/lib/rpm", "usr/lib/sysimage/rpm"];
+
+/// Installed packages in `chroot`, one NEVRA per line
+fn rpm_packages(chroot: &Path) -> Result<String> {
+	let list = cmd_lib::run_fun!(rpm --root=$chroot -qa --qf "%{NEVRA}\n")?;
+	let mut packages: Vec<_> = list.lines().collect();
+	packages.sort_unstable();
+	Ok(packages.join("\n") + "\n")
+}
 
-			// crate::chroot_run_cmd!(&chroot,
-			// 	rm -f /etc/default/grub;
-			// )?;
+/// Removes the RPM database from `chroot` once the package list from `packages` is written
+fn strip_rpmdb(chroot: &Path, packages: impl FnOnce(&Path) -> Result<String>) -> Result<()> {
+	info!("Recording package list");
+	just_write(chroot.join(PACKAGE_LIST), packages(chroot)?)?;
+
+	info!("Removing RPM database");
+	for dir in RPMDB_DIRS {
+		let dir = chroot.join(dir);
+		// /var/lib/rpm is a symlink to the other one on newer releases
+		if dir.is_symlink() {
+			fs::remove_file(&dir)?;
+		} else if dir.exists() {
+			fs::remove_dir_all(&dir)?;
 		}
+	}
+	Ok(())
+}
 
-		// now, let's run some funny post-install scripts
+const GRUB_CFG: &str = "boot/grub2/grub.cfg";
 
-		info!("Running post-install scripts");
+/// Runs `grub2-mkconfig` in the chroot, then checks that it actually wrote the config
+fn grub_mkconfig(chroot: &Path) {
+	let mkconfig = |mut cmd: std::process::Command| -> Result<()> {
+		let status = cmd.status()?;
+		if !status.success() {
+			// While grub2-mkconfig may not return 0 it should still work
+			warn!(%status, "grub2-mkconfig not returning 0, checking output anyway");
+		}
+		Ok(())
+	};
+
+	let mut direct = std::process::Command::new("grub2-mkconfig");
+	direct.arg("-o").arg(format!("/{GRUB_CFG}"));
+
+	// It sometimes doesn't write the file at all, but works when run inside a post script,
+	// so retry as one
+	let script = Script {
+		id: Some("grub2-mkconfig".to_string()),
+		inline: Some(format!("#!/bin/sh\ngrub2-mkconfig -o /{GRUB_CFG}\n")),
+		chroot: Some(true),
+		..Script::default()
+	};
+
+	let written = run_verify_output(
+		&chroot.join(GRUB_CFG),
+		|| crate::util::enter_chroot_run(chroot, || mkconfig(direct)),
+		|| run_script(script, chroot, true),
+	);
+
+	if !written {
+		warn!(path = ?chroot.join(GRUB_CFG), "grub2-mkconfig did not generate a config, the image may not boot");
+	}
+}
+
+/// Runs `run`, then `retry` if `path` is missing or empty afterwards.
+///
+/// Returns whether `path` was written.
+fn run_verify_output(
+	path: &Path, run: impl FnOnce() -> Result<()>, retry: impl FnOnce() -> Result<()>,
+) -> bool {
+	let written = || fs::metadata(path).is_ok_and(|m| m.len() > 0);
+
+	if let Err(e) = run() {
+		warn!(?e, ?path, "Command failed");
+	}
+	if written() {
+		return true;
+	}
 
-		run_all_scripts(&manifest.scripts.post, &chroot, true)
+	warn!(?path, "Output missing or empty, retrying");
+	if let Err(e) = retry() {
+		warn!(?e, ?path, "Retry failed");
 	}
+	written()
+}
+
+#[test]
+fn test_run_verify_output() {
+	let tmp = crate::util::TestDir::new("verify");
+	let path = tmp.join("grub.cfg");
+
+	let mut retried = false;
+	let written = run_verify_output(
+		&path,
+		|| Ok(()),
+		|| {
+			retried = true;
+			Ok(fs::write(&path, "menuentry")?)
+		},
+	);
+	assert!(retried && written);
+
+	// output is there the first time, so no retry
+	let written = run_verify_output(&path, || Ok(()), || unreachable!());
+	assert!(written);
+
+	fs::write(&path, "").unwrap();
+	assert!(!run_verify_output(&path, || Ok(()), || Ok(())));
 }
 
 #[tracing::instrument(skip(chroot, is_post))]
@@ -461,7 +1052,14 @@ pub fn run_all_scripts(scrs: &[Script], chroot: &Path, is_post: bool) -> Result<
 
 #[tracing::instrument]
 pub fn run_scripts(
-	mut scripts: IndexMap<&str, (Script, bool)>, chroot: &Path, is_post: bool,
+	scripts: IndexMap<&str, (Script, bool)>, chroot: &Path, is_post: bool,
+) -> Result<()> {
+	run_scripts_with(scripts, &|script| run_script(script, chroot, is_post))
+}
+
+/// Runs `scripts` in order with `run`, each after the scripts it needs
+fn run_scripts_with(
+	mut scripts: IndexMap<&str, (Script, bool)>, run: &dyn Fn(Script) -> Result<()>,
 ) -> Result<()> {
 	trace!("Running scripts");
 	for idx in scripts.clone().keys() {
@@ -490,11 +1088,17 @@ pub fn run_scripts(
 		}
 
 		// Run needs
-		run_scripts(needs, chroot, is_post)?;
+		run_scripts_with(needs, run)?;
 
 		// Run the actual script
 		let Some((scr, done)) = scripts.get_mut(idx) else { unreachable!() };
-		run_script(std::mem::take(scr), chroot, is_post)?;
+		let ignore_errors = scr.ignore_errors;
+		if let Err(e) = run(std::mem::take(scr)) {
+			if !ignore_errors {
+				return Err(e);
+			}
+			warn!(?e, idx, "Script failed, continuing since `ignore_errors` is set");
+		}
 		*done = true;
 	}
 	Ok(())
@@ -518,8 +1122,12 @@ impl ImageBuilder for DiskImageBuilder {
 		&self, chroot: &Path, image: &Path, manifest: &Manifest, _: &SkipPhases,
 	) -> Result<()> {
 		// create sparse file on disk
-		bail_let!(Some(disk) = &manifest.disk => "Disk layout not specified");
-		bail_let!(Some(disk_size) = &disk.size => "Disk size not specified");
+		bail_let!(Some(disk) = &manifest.disk => crate::config::NO_DISK_LAYOUT);
+		if self.bootloader == Bootloader::Syslinux {
+			bail!(crate::config::SYSLINUX_NO_DISK);
+		}
+		let disk_size = disk.disk_size()?;
+		let disk = &disk.with_verity()?;
 		let sparse_path = &image.canonicalize()?.join("katsu.img");
 		crate::util::create_sparse(sparse_path, disk_size.as_u64())?;
 
@@ -528,18 +1136,48 @@ impl ImageBuilder for DiskImageBuilder {
 		// 	disk.mount_to_chroot(&loopdev.path().unwrap(), &chroot)?;
 		// 	disk.unmount_from_chroot(&loopdev.path().unwrap(), &chroot)?;
 		// }
-		let uefi = { self.bootloader != Bootloader::GrubBios };
+		let uefi = { !matches!(self.bootloader, Bootloader::GrubBios) };
 		let arch = manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH);
 
 		let (ldp, hdl) = loopdev_with_file(sparse_path)?;
 
 		// Partition disk
-		disk.apply(&ldp, arch)?;
+		let mut summary = disk.apply(&ldp, arch)?;
 
 		// Mount partitions to chroot
 		disk.mount_to_chroot(&ldp, chroot)?;
 
-		self.root_builder.build(&chroot.canonicalize()?, manifest)?;
+		let mut manifest = manifest.clone();
+		if let Some(root) = disk.root_cmdline(&summary)? {
+			let cmdline =
+				manifest.kernel_cmdline.take().map_or(root.clone(), |cmd| format!("{root} {cmd}"));
+			manifest.kernel_cmdline = Some(cmdline);
+		}
+
+		self.root_builder.build(&chroot.canonicalize()?, &manifest)?;
+
+		if manifest.dracut.hostonly {
+			IsoBuilder::dracut(chroot, true)?;
+		} else if manifest.kernel_modules.depmod_after_scripts {
+			info!("Regenerating initramfs after the scripts' module changes");
+			crate::util::enter_chroot_run(chroot, || -> Result<()> {
+				cmd_lib::run_cmd!(dracut -f --regenerate-all 2>&1)?;
+				Ok(())
+			})?;
+		}
+		manifest.dracut.cleanup(chroot)?;
+
+		if let Some(uboot) = &disk.uboot {
+			install_uboot(chroot, uboot)?;
+		}
+		if !disk.dtbs.is_empty() {
+			let (vmlinuz, _) = find_vmlinuz_initramfs(chroot)?;
+			info!("Copying device trees");
+			cp_dtbs(chroot, disk, vmlinuz.trim_start_matches("vmlinuz-"))?;
+		}
+		if let Some(extlinux) = disk.extlinux_config() {
+			write_extlinux(chroot, &manifest, &extlinux)?;
+		}
 
 		if !uefi {
 			info!("Not UEFI, Setting up extra configs");
@@ -550,14 +1188,28 @@ impl ImageBuilder for DiskImageBuilder {
 			std::process::Command::new("grub2-install")
 				.arg("--target=i386-pc")
 				.arg(format!("--boot-directory={}", chroot.join("boot").display()))
-				.arg(ldp)
+				.arg(&ldp)
 				.output()
 				.map_err(|e| color_eyre::eyre::eyre!("Failed to execute grub2-install: {}", e))?;
 		}
 
+		disk.create_snapshots(chroot)?;
+
 		disk.unmount_from_chroot(chroot)?;
 
+		disk.format_verity(&ldp, &mut summary)?;
+
 		drop(hdl);
+
+		if let Some(mut cmd) =
+			compress_cmd(sparse_path, &manifest.compression(OutputFormat::DiskImage))?
+		{
+			info!(?cmd, "Compressing disk image");
+			let status = cmd.status()?;
+			if !status.success() {
+				bail!("Compressing the disk image failed with {status}");
+			}
+		}
 		Ok(())
 	}
 }
@@ -605,6 +1257,13 @@ impl ImageBuilder for FsBuilder {
 		}
 
 		self.root_builder.build(out, manifest)?;
+
+		if manifest.preserve_permissions {
+			let mut pf = out.as_os_str().to_owned();
+			pf.push(".pf");
+			info!(path = ?pf, "Recording ownership and permissions");
+			just_write(&pf, crate::util::pseudo_file(out)?)?;
+		}
 		Ok(())
 	}
 }
@@ -619,7 +1278,39 @@ const DR_OMIT: &str = "";
 const DR_ARGS: &str = "--xz --no-early-microcode";
 
 impl IsoBuilder {
-	fn dracut(&self, root: &Path) -> Result<()> {
+	/// Arguments for `dracut`, generic (`-N`) with the live modules unless `hostonly` is set
+	fn dracut_args(hostonly: bool) -> Vec<String> {
+		// set dracut options
+		// this is kind of a hack, but uhh it works maybe
+		// todo: make this properly configurable without envvars
+
+		let dr_mods = env_flag!("KATSU_DRACUT_MODS").unwrap_or(DR_MODS.to_string());
+		let dr_omit = env_flag!("KATSU_DRACUT_OMIT").unwrap_or(DR_OMIT.to_string());
+
+		let dr_extra_args = env_flag!("KATSU_DRACUT_ARGS").unwrap_or("".to_string());
+		let binding = env_flag!("KATSU_DRACUT_ARGS").unwrap_or(DR_ARGS.to_string());
+		let dr_basic_args = binding.split(' ').map(str::to_string);
+
+		// combine them all into one string
+
+		let mut dr_args: Vec<String> = dr_basic_args.collect();
+		dr_args.extend(["--nomdadmconf", "--nolvmconf"].map(str::to_string));
+		if hostonly {
+			// host-specific initramfs for a disk image, the live modules aren't needed
+			dr_args.push("-fH".to_string());
+		} else {
+			dr_args.extend(["-fN".to_string(), "-a".to_string(), dr_mods]);
+		}
+		dr_args.push(dr_extra_args);
+		if !dr_omit.is_empty() {
+			dr_args.push("--omit".to_string());
+			dr_args.push(dr_omit);
+		}
+		dr_args
+	}
+
+	#[tracing::instrument(target = "katsu::dracut")]
+	pub fn dracut(root: &Path, hostonly: bool) -> Result<()> {
 		info!(?root, "Generating initramfs");
 		bail_let!(
 			Some(kver) = fs::read_dir(root.join("boot"))?.find_map(|f| {
@@ -641,29 +1332,7 @@ impl IsoBuilder {
 			}) => "Can't find initramfs in /boot."
 		);
 
-		// set dracut options
-		// this is kind of a hack, but uhh it works maybe
-		// todo: make this properly configurable without envvars
-
-		let dr_mods = env_flag!("KATSU_DRACUT_MODS").unwrap_or(DR_MODS.to_string());
-		let dr_omit = env_flag!("KATSU_DRACUT_OMIT").unwrap_or(DR_OMIT.to_string());
-
-		let dr_extra_args = env_flag!("KATSU_DRACUT_ARGS").unwrap_or("".to_string());
-		let binding = env_flag!("KATSU_DRACUT_ARGS").unwrap_or(DR_ARGS.to_string());
-		let dr_basic_args = binding.split(' ').collect::<Vec<_>>();
-
-		// combine them all into one string
-
-		let dr_args2 = vec!["--nomdadmconf", "--nolvmconf", "-fN", "-a", &dr_mods, &dr_extra_args];
-		let mut dr_args = vec![];
-
-		dr_args.extend(dr_basic_args);
-
-		dr_args.extend(dr_args2);
-		if !dr_omit.is_empty() {
-			dr_args.push("--omit");
-			dr_args.push(&dr_omit);
-		}
+		let dr_args = Self::dracut_args(hostonly);
 
 		crate::util::enter_chroot_run(root, || -> Result<()> {
 			std::process::Command::new("dracut")
@@ -678,64 +1347,121 @@ impl IsoBuilder {
 		Ok(())
 	}
 
-	pub fn squashfs(&self, chroot: &Path, image: &Path) -> Result<()> {
-		// Extra configurable options, for now we use envars
-		// todo: document these
+	pub fn squashfs(chroot: &Path, image: &Path, compression: &Compression) -> Result<()> {
+		// Extra mksquashfs options, for now we use an envar
+		// todo: document this
 
-		let sqfs_comp = env_flag!("KATSU_SQUASHFS_ARGS").unwrap_or("zstd".to_string());
-
-		info!("Determining squashfs options");
-
-		let sqfs_comp_args = match sqfs_comp.as_str() {
-			"gzip" => "-comp gzip -Xcompression-level 9",
-			"lzo" => "-comp lzo",
-			"lz4" => "-comp lz4 -Xhc",
-			"xz" => "-comp xz -Xbcj x86",
-			"zstd" => "-comp zstd -Xcompression-level 19",
-			"lzma" => "-comp lzma",
-			_ => bail!("Unknown squashfs compression: {sqfs_comp}"),
-		}
-		.split(' ')
-		.collect::<Vec<_>>();
+		info!(?compression, "Determining squashfs options");
+		let sqfs_comp_args = compression.mksquashfs_args();
 
 		let binding = env_flag!("KATSU_SQUASHFS_ARGS").unwrap_or("".to_string());
 		let sqfs_extra_args = binding.split(' ').collect::<Vec<_>>();
 
-		info!("Squashing file system (mksquashfs)");
-		std::process::Command::new("mksquashfs")
-			.arg(chroot)
-			.arg(image)
-			.args(&sqfs_comp_args)
-			.arg("-b")
-			.arg("1048576")
-			.arg("-noappend")
-			.arg("-e")
-			.arg("/dev/")
-			.arg("-e")
-			.arg("/proc/")
-			.arg("-e")
-			.arg("/sys/")
-			.arg("-p")
-			.arg("/dev 755 0 0")
-			.arg("-p")
-			.arg("/proc 755 0 0")
-			.arg("-p")
-			.arg("/sys 755 0 0")
-			.args(&sqfs_extra_args)
-			.status()?;
+		let squash = || -> Result<()> {
+			info!("Squashing file system (mksquashfs)");
+			std::process::Command::new("mksquashfs")
+				.arg(chroot)
+				.arg(image)
+				.args(&sqfs_comp_args)
+				.arg("-b")
+				.arg("1048576")
+				.arg("-noappend")
+				.arg("-e")
+				.arg("/dev/")
+				.arg("-e")
+				.arg("/proc/")
+				.arg("-e")
+				.arg("/sys/")
+				.arg("-p")
+				.arg("/dev 755 0 0")
+				.arg("-p")
+				.arg("/proc 755 0 0")
+				.arg("-p")
+				.arg("/sys 755 0 0")
+				.args(&sqfs_extra_args)
+				.status()?;
+			Ok(())
+		};
+
+		match crate::util::RootimgCache::from_env() {
+			Some(cache) => {
+				let opts =
+					format!("squashfs {} {}", sqfs_comp_args.join(" "), sqfs_extra_args.join(" "));
+				cache.get_or_build(chroot, &opts, image, squash)?;
+			},
+			None => squash()?,
+		}
 
 		Ok(())
 	}
+	/// Not used yet, root images are always squashfs
 	#[allow(dead_code)]
-	pub fn erofs(&self, chroot: &Path, image: &Path) -> Result<()> {
-		std::process::Command::new("mkfs.erofs")
-			.arg("-d")
-			.arg(chroot)
-			.arg("-o")
-			.arg(image)
-			.status()?;
+	pub fn erofs(chroot: &Path, image: &Path, compression: &Compression) -> Result<()> {
+		let args = compression.mkfs_erofs_args()?;
+		cmd_lib::run_cmd!(mkfs.erofs $[args] $image $chroot 2>&1)?;
 		Ok(())
 	}
+
+	/// Builds the `xorrisofs` command for GRUB ISOs, booting with shim on UEFI
+	fn grub_xorrisofs_cmd(
+		chroot: &Path, image: &Path, manifest: &Manifest,
+	) -> std::process::Command {
+		let volid = manifest.get_volid();
+		let (_, bios_bin) = Bootloader::Grub.get_bins();
+		let tree = chroot.parent().unwrap().join(ISO_TREE);
+		let efiboot = tree.join("boot/efiboot.img");
+		let grub2_mbr_hybrid = chroot.join("usr/lib/grub/i386-pc/boot_hybrid.img");
+		let gpt_only = manifest.iso.as_ref().is_some_and(|iso| iso.gpt_only);
+
+		let arch = manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+		let arch_args = match arch {
+			// Hybrid mode is only supported on x86_64
+			"x86_64" if !gpt_only => vec!["--grub2-mbr", grub2_mbr_hybrid.to_str().unwrap()],
+			"x86_64" | "aarch64" => vec![],
+			_ => unimplemented!(),
+		};
+
+		let mut cmd = std::process::Command::new("xorrisofs");
+		cmd.arg("-R")
+			.arg("-V")
+			.arg(&volid)
+			.args(&arch_args)
+			.arg("-partition_offset")
+			.arg("16")
+			.arg("-appended_part_as_gpt")
+			.arg("-append_partition")
+			.arg("2")
+			.arg("C12A7328-F81F-11D2-BA4B-00A0C93EC93B")
+			.arg(&efiboot)
+			.arg("-iso_mbr_part_type")
+			.arg("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7")
+			.arg("-c")
+			.arg("boot.cat")
+			.arg("--boot-catalog-hide");
+
+		// There's no BIOS GRUB on aarch64, so the EFI image is the only El Torito entry
+		if arch == "x86_64" {
+			cmd.arg("-b")
+				.arg(bios_bin)
+				.arg("-no-emul-boot")
+				.arg("-boot-load-size")
+				.arg("4")
+				.arg("-boot-info-table")
+				.arg("--grub2-boot-info")
+				.arg("-eltorito-alt-boot");
+		}
+
+		cmd.arg("-e")
+			.arg("--interval:appended_partition_2:all::")
+			.arg("-no-emul-boot")
+			.arg("-vvvvv")
+			.arg("--md5")
+			.arg(&tree)
+			.arg("-o")
+			.arg(image);
+		cmd
+	}
+
 	// TODO: add mac support
 	pub fn xorriso(&self, chroot: &Path, image: &Path, manifest: &Manifest) -> Result<()> {
 		info!("Generating ISO image");
@@ -743,10 +1469,6 @@ impl IsoBuilder {
 		let (uefi_bin, bios_bin) = self.bootloader.get_bins();
 		let tree = chroot.parent().unwrap().join(ISO_TREE);
 
-		// TODO: refactor to new fn in Bootloader
-		let grub2_mbr_hybrid = chroot.join("usr/lib/grub/i386-pc/boot_hybrid.img");
-		let efiboot = tree.join("boot/efiboot.img");
-
 		match self.bootloader {
 			Bootloader::Grub => {
 				// cmd_lib::run_cmd!(grub2-mkrescue -o $image $tree -volid $volid 2>&1)?;
@@ -760,45 +1482,24 @@ impl IsoBuilder {
 				// 2. EFI partition (fat12)
 				// 3. data
 
-				let arch_args = match manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH)
-				{
-					// Hybrid mode is only supported on x86_64
-					"x86_64" => vec!["--grub2-mbr", grub2_mbr_hybrid.to_str().unwrap()],
-					"aarch64" => vec![],
-					_ => unimplemented!(),
-				};
-
-				std::process::Command::new("xorrisofs")
+				Self::grub_xorrisofs_cmd(chroot, image, manifest).status()?;
+			},
+			Bootloader::Syslinux => {
+				std::process::Command::new("xorriso")
+					.arg("-as")
+					.arg("mkisofs")
 					.arg("-R")
 					.arg("-V")
-					.arg(&volid)
-					.args(&arch_args)
-					.arg("-partition_offset")
-					.arg("16")
-					.arg("-appended_part_as_gpt")
-					.arg("-append_partition")
-					.arg("2")
-					.arg("C12A7328-F81F-11D2-BA4B-00A0C93EC93B")
-					.arg(&efiboot)
-					.arg("-iso_mbr_part_type")
-					.arg("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7")
-					.arg("-c")
-					.arg("boot.cat")
-					.arg("--boot-catalog-hide")
+					.arg(volid)
 					.arg("-b")
 					.arg(bios_bin)
+					.arg("-c")
+					.arg("isolinux/boot.cat")
 					.arg("-no-emul-boot")
 					.arg("-boot-load-size")
 					.arg("4")
 					.arg("-boot-info-table")
-					.arg("--grub2-boot-info")
-					.arg("-eltorito-alt-boot")
-					.arg("-e")
-					.arg("--interval:appended_partition_2:all::")
-					.arg("-no-emul-boot")
-					.arg("-vvvvv")
-					.arg("--md5")
-					.arg(&tree)
+					.arg(tree)
 					.arg("-o")
 					.arg(image)
 					.status()?;
@@ -843,6 +1544,311 @@ impl IsoBuilder {
 }
 
 const ISO_TREE: &str = "iso-tree";
+const DEFAULT_ISO_OUT: &str = "out.iso";
+const DEFAULT_PXE_OUT: &str = "netboot";
+const DISK_IMAGE_OUT: &str = "./katsu-work/image/katsu.img";
+
+/// Builds the command compressing `image` next to itself (e.g. `katsu.img.xz`), keeping the
+/// raw image. Nothing is done without compression, see [`Compression::resolve`] for the level
+fn compress_cmd(image: &Path, compression: &Compression) -> Result<Option<std::process::Command>> {
+	let program = match compression.algorithm {
+		Compressor::None => return Ok(None),
+		Compressor::Xz => "xz",
+		Compressor::Zstd => "zstd",
+		Compressor::Gzip => "gzip",
+		algorithm => bail!("Disk images can't be compressed with {algorithm:?}"),
+	};
+	let mut cmd = std::process::Command::new(program);
+	if program != "gzip" {
+		cmd.arg("-T0");
+	}
+	cmd.arg("-k").arg("-f");
+	if let Some(level) = compression.level {
+		cmd.arg(format!("-{level}"));
+	}
+	cmd.arg(image);
+	Ok(Some(cmd))
+}
+
+/// The compressed copy of the disk image written by [`compress_cmd`]
+fn compressed_image(image: &Path, compression: &Compression) -> Option<PathBuf> {
+	let ext = match compression.algorithm {
+		Compressor::Xz => "xz",
+		Compressor::Zstd => "zst",
+		Compressor::Gzip => "gz",
+		_ => return None,
+	};
+	let mut path = image.as_os_str().to_owned();
+	path.push(format!(".{ext}"));
+	Some(path.into())
+}
+
+/// Copies [`IsoConfig::root_files`] into the ISO tree
+fn cp_iso_root_files(manifest: &Manifest, tree: &Path) -> Result<()> {
+	let Some(iso) = &manifest.iso else { return Ok(()) };
+	for file in &iso.root_files {
+		let dest = file.dest_in(tree)?;
+		debug!(source = ?file.source, ?dest, "Copying file to ISO root");
+		fs::create_dir_all(dest.parent().unwrap())?;
+		fs::copy(&file.source, &dest)?;
+	}
+	Ok(())
+}
+
+/// Renders `extlinux.conf` for U-Boot's distro boot
+fn extlinux_conf(
+	manifest: &Manifest, extlinux: &ExtlinuxConfig, vmlinuz: &str, initramfs: &str,
+) -> Result<String> {
+	let distro = &manifest.distro.as_ref().map_or("Linux", |s| s);
+	let cmd = &manifest.kernel_cmdline.as_ref().map_or("", |s| s);
+	let fdt = extlinux.fdt.as_ref().map(|p| p.display().to_string());
+	// paths are relative to the partition holding /boot
+	let separate_boot = manifest.disk.as_ref().is_some_and(|d| d.get_index("/boot").is_some());
+	let prefix = if separate_boot { "" } else { "/boot" };
+	Ok(
+		crate::tpl!("extlinux.conf.tera" => { EXTLINUX_PREPEND_COMMENT, distro, prefix, vmlinuz, initramfs, fdt, cmd }),
+	)
+}
+
+/// Writes `/boot/extlinux/extlinux.conf` for the installed kernel
+fn write_extlinux(chroot: &Path, manifest: &Manifest, extlinux: &ExtlinuxConfig) -> Result<()> {
+	info!("Writing extlinux.conf");
+	let (vmlinuz, initramfs) = find_vmlinuz_initramfs(chroot)?;
+	just_write(
+		chroot.join("boot/extlinux/extlinux.conf"),
+		extlinux_conf(manifest, extlinux, &vmlinuz, &initramfs)?,
+	)
+}
+
+/// Copies the board's device trees to `/boot/dtb`, and next to U-Boot for the board firmware
+fn cp_dtbs(chroot: &Path, disk: &PartitionLayout, kver: &str) -> Result<()> {
+	let boot_dtb = chroot.join("boot/dtb");
+	let modules_dtb = chroot.join("usr/lib/modules").join(kver).join("dtb");
+	let src_dir = if modules_dtb.is_dir() { &modules_dtb } else { &boot_dtb };
+	for dtb in &disk.dtbs {
+		if dtb.components().any(|c| !matches!(c, std::path::Component::Normal(_))) {
+			bail!("Invalid device tree path: {}", dtb.display());
+		}
+		let src = src_dir.join(dtb);
+		if !src.is_file() {
+			bail!("Device tree {} not found in {}", dtb.display(), src_dir.display());
+		}
+		debug!(?src, "Copying device tree");
+		if src_dir != &boot_dtb {
+			let dest = boot_dtb.join(dtb);
+			fs::create_dir_all(dest.parent().unwrap())?;
+			fs::copy(&src, dest)?;
+		}
+		if let Some(uboot) = &disk.uboot {
+			let firmware_dir = &uboot.firmware_dir;
+			let firmware_dir = chroot.join(firmware_dir.strip_prefix("/").unwrap_or(firmware_dir));
+			fs::create_dir_all(&firmware_dir)?;
+			fs::copy(&src, firmware_dir.join(dtb.file_name().unwrap()))?;
+		}
+	}
+	Ok(())
+}
+
+/// Copies the board's U-Boot to the firmware partition
+fn install_uboot(chroot: &Path, uboot: &UBootConfig) -> Result<()> {
+	let binary = chroot.join(uboot.binary()?);
+	if !binary.is_file() {
+		bail!(
+			"U-Boot binary for {} not found at {}, is uboot-images-armv8 installed?",
+			uboot.board,
+			binary.display()
+		);
+	}
+	let firmware_dir = &uboot.firmware_dir;
+	let firmware_dir = chroot.join(firmware_dir.strip_prefix("/").unwrap_or(firmware_dir));
+	info!(board = uboot.board, ?firmware_dir, "Installing U-Boot");
+	fs::create_dir_all(&firmware_dir)?;
+	fs::copy(&binary, firmware_dir.join("u-boot.bin"))?;
+	Ok(())
+}
+
+const BLS_ENTRIES: &str = "boot/loader/entries";
+
+/// Kernel options for BLS entries booting the live media
+fn live_bls_options(manifest: &Manifest) -> String {
+	let volid = manifest.get_volid();
+	let live_args = manifest.get_live_args();
+	let live_args = if live_args.is_empty() { live_args } else { format!(" {live_args}") };
+	let cmd = manifest.kernel_cmdline.as_deref().unwrap_or_default();
+	format!("root=live:CDLABEL={volid} rd.live.image{live_args} enforcing=0 {cmd}")
+		.trim_end()
+		.to_string()
+}
+
+/// Rewrites a BLS entry from the chroot to boot the live media with `options`
+///
+/// The kernel and initrds are always in `/boot` on the ISO, wherever the entry expected them.
+fn live_bls_entry(entry: &str, options: &str) -> String {
+	let file_name = |p: &str| {
+		Path::new(p).file_name().map_or(p.to_string(), |f| f.to_string_lossy().into_owned())
+	};
+	entry
+		.lines()
+		.map(|line| match line.split_once(char::is_whitespace) {
+			Some((key @ ("linux" | "initrd"), paths)) => {
+				let paths: Vec<_> =
+					paths.split_whitespace().map(|p| format!("/boot/{}", file_name(p))).collect();
+				format!("{key} {}", paths.join(" "))
+			},
+			Some(("options", _)) => format!("options {options}"),
+			_ => line.to_string(),
+		})
+		.map(|line| line + "\n")
+		.collect()
+}
+
+/// Copies the chroot's BLS entries into the ISO tree `tree`, rewritten by [`live_bls_entry`]
+fn cp_bls_entries(chroot: &Path, tree: &Path, options: &str) -> Result<()> {
+	let src = chroot.join(BLS_ENTRIES);
+	if !src.is_dir() {
+		warn!("No BLS entries in the chroot, the GRUB menu only has the generated entries");
+		return Ok(());
+	}
+	let dest = tree.join(BLS_ENTRIES);
+	fs::create_dir_all(&dest)?;
+	for entry in fs::read_dir(src)? {
+		let path = entry?.path();
+		if path.extension().is_some_and(|ext| ext == "conf") {
+			debug!(?path, "Copying BLS entry");
+			let conf = fs::read_to_string(&path)?;
+			just_write(dest.join(path.file_name().unwrap()), live_bls_entry(&conf, options))?;
+		}
+	}
+	Ok(())
+}
+
+/// Where the `limine` package installs its boot files on the host
+const LIMINE_DIR: &str = "/usr/share/limine";
+const LIMINE_FILES: &[&str] = &["limine-uefi-cd.bin", "limine-bios-cd.bin", "limine-bios.sys"];
+
+/// Where the `syslinux` package installs its BIOS modules, relative to the chroot
+const SYSLINUX_DIR: &str = "usr/share/syslinux";
+const SYSLINUX_FILES: &[&str] = &["isolinux.bin", "ldlinux.c32", "menu.c32", "libutil.c32"];
+
+/// Builds the command to create a detached, ASCII-armored signature of `image`
+fn gpg_sign_cmd(key: &str, image: &Path) -> std::process::Command {
+	let mut sig = image.as_os_str().to_owned();
+	sig.push(".asc");
+	let mut cmd = std::process::Command::new("gpg");
+	cmd.arg("--batch")
+		.arg("--yes")
+		.arg("--local-user")
+		.arg(key)
+		.arg("--detach-sign")
+		.arg("--armor")
+		.arg("--output")
+		.arg(sig)
+		.arg(image);
+	cmd
+}
+
+fn gpg_sign(key: &str, image: &Path) -> Result<()> {
+	info!(key, ?image, "Signing image");
+	let status = gpg_sign_cmd(key, image).status()?;
+	if !status.success() {
+		bail!("gpg failed to sign {image:?} with key {key}: {status}");
+	}
+	Ok(())
+}
+
+/// Checks that a secret key is in the host keyring
+fn gpg_check_key(key: &str) -> Result<()> {
+	let out = std::process::Command::new("gpg").arg("--list-secret-keys").arg(key).output()?;
+	if !out.status.success() {
+		bail!("GPG secret key `{key}` not found in the host keyring");
+	}
+	Ok(())
+}
+
+/// Checks the ISO against [`IsoConfig::media_size`], unless no ISO was built
+fn check_media_size(image: &Path, iso: &IsoConfig) -> Result<()> {
+	let Some(media) = iso.media_size else { return Ok(()) };
+	if !image.exists() {
+		warn!(?image, "No ISO to check against the media size");
+		return Ok(());
+	}
+	media.check(fs::metadata(image)?.len(), iso.fail_if_larger_than_media)
+}
+
+/// Builds the QEMU command booting `image` headless, with the serial console on stdout
+fn smoke_test_cmd(
+	image: &Path, arch: &str, cfg: &SmokeTestConfig,
+) -> Result<std::process::Command> {
+	let machine = match arch {
+		"x86_64" => "q35,accel=kvm:tcg",
+		"aarch64" => "virt,accel=kvm:tcg",
+		_ => bail!("Smoke tests are not supported for {arch}"),
+	};
+	let firmware = cfg.get_firmware(arch)?;
+	let mut cmd = std::process::Command::new(format!("qemu-system-{arch}"));
+	cmd.arg("-machine")
+		.arg(machine)
+		.arg("-cpu")
+		.arg("max")
+		.arg("-m")
+		.arg(cfg.memory.unwrap_or(2048).to_string())
+		.arg("-drive")
+		.arg(format!("if=pflash,format=raw,readonly=on,file={}", firmware.display()))
+		.arg("-cdrom")
+		.arg(image)
+		.arg("-display")
+		.arg("none")
+		.arg("-serial")
+		.arg("stdio")
+		.arg("-no-reboot");
+	Ok(cmd)
+}
+
+/// Checks that QEMU and the firmware for the smoke test are on the host
+fn smoke_test_check(arch: &str, cfg: &SmokeTestConfig) -> Result<()> {
+	let qemu = format!("qemu-system-{arch}");
+	if crate::util::find_in_path(&qemu).is_none() {
+		bail!("`{qemu}` is required for the smoke test");
+	}
+	let firmware = cfg.get_firmware(arch)?;
+	if !firmware.exists() {
+		bail!("UEFI firmware {firmware:?} for the smoke test not found, is edk2-ovmf installed?");
+	}
+	Ok(())
+}
+
+/// Boots `image` in QEMU, succeeding once the marker shows up on the serial console
+fn smoke_test(image: &Path, arch: &str, cfg: &SmokeTestConfig) -> Result<()> {
+	use std::io::BufRead;
+
+	let marker = cfg.get_marker().to_string();
+	info!(?image, marker, "Booting image in QEMU");
+	let mut child = smoke_test_cmd(image, arch, cfg)?
+		.stdin(std::process::Stdio::null())
+		.stdout(std::process::Stdio::piped())
+		.spawn()?;
+	let stdout = child.stdout.take().unwrap();
+
+	let (tx, rx) = std::sync::mpsc::channel();
+	std::thread::spawn(move || {
+		for line in std::io::BufReader::new(stdout).lines().map_while(std::io::Result::ok) {
+			trace!(line, "serial");
+			if line.contains(&marker) {
+				let _ = tx.send(());
+				return;
+			}
+		}
+	});
+
+	let booted = rx.recv_timeout(cfg.get_timeout()).is_ok();
+	let _ = child.kill();
+	let _ = child.wait();
+	if !booted {
+		bail!("Smoke test failed: `{}` not seen on the serial console", cfg.get_marker());
+	}
+	info!("Smoke test passed");
+	Ok(())
+}
 
 impl ImageBuilder for IsoBuilder {
 	fn build(
@@ -851,29 +1857,67 @@ impl ImageBuilder for IsoBuilder {
 		crate::gen_phase!(skip_phases);
 		// You can now skip phases by adding environment variable `KATSU_SKIP_PHASES` with a comma-separated list of phases to skip
 
-		let image = PathBuf::from(manifest.out_file.as_ref().map_or("out.iso", |s| s));
+		let image = PathBuf::from(manifest.out_file.as_ref().map_or(DEFAULT_ISO_OUT, |s| s));
 		// Create workspace directory
 		let workspace = chroot.parent().unwrap().to_path_buf();
 		debug!("Workspace: {workspace:#?}");
 		fs::create_dir_all(&workspace)?;
 
+		let arch = manifest.dnf.arch.as_deref().unwrap_or(std::env::consts::ARCH);
+		let check_bootloader = !skip_phases.contains("copy-live");
+		let sign_key = manifest.iso.as_ref().and_then(|iso| iso.sign_key.as_deref());
+
+		if let Some(key) = sign_key.filter(|_| !skip_phases.contains("sign")) {
+			gpg_check_key(key)?;
+		}
+
+		let smoke_test_cfg = manifest.iso.as_ref().and_then(|iso| iso.smoke_test.as_ref());
+		if let Some(cfg) = smoke_test_cfg.filter(|_| !skip_phases.contains("smoke-test")) {
+			smoke_test_check(arch, cfg)?;
+		}
+
+		// Fail early instead of after the root build if the host can't make the boot files
+		if check_bootloader {
+			self.bootloader.check_host(arch, &manifest.iso.clone().unwrap_or_default())?;
+		}
+
 		phase!("root": self.root_builder.build(chroot, manifest));
 		// self.root_builder.build(chroot.canonicalize()?.as_path(), manifest)?;
 
-		phase!("dracut": self.dracut(chroot));
+		if check_bootloader {
+			let iso = manifest.iso.clone().unwrap_or_default();
+			self.bootloader.check_chroot(chroot, arch, &iso)?;
+		}
+
+		phase!("dracut": Self::dracut(chroot, false));
+		manifest.dracut.cleanup(chroot)?;
 
 		// temporarily store content of iso
-		let image_dir = workspace.join(ISO_TREE).join("LiveOS");
-		fs::create_dir_all(&image_dir)?;
+		let rootfs = workspace.join(ISO_TREE).join(manifest.get_rootfs_path());
+		fs::create_dir_all(rootfs.parent().unwrap())?;
 
-		phase!("rootimg": self.squashfs(chroot, &image_dir.join("squashfs.img")));
+		phase!("rootimg": Self::squashfs(chroot, &rootfs, &manifest.compression(OutputFormat::Iso)));
 
 		phase!("copy-live": self.bootloader.copy_liveos(manifest, chroot));
 
+		phase!("root-files": cp_iso_root_files(manifest, &workspace.join(ISO_TREE)));
+
 		phase!("iso": self.xorriso(chroot, &image, manifest));
 
 		phase!("bootloader": self.bootloader.install(&image));
 
+		if let Some(iso) = manifest.iso.as_ref().filter(|iso| iso.media_size.is_some()) {
+			phase!("media-size": check_media_size(&image, iso));
+		}
+
+		if let Some(key) = sign_key {
+			phase!("sign": gpg_sign(key, &image));
+		}
+
+		if let Some(cfg) = smoke_test_cfg {
+			phase!("smoke-test": smoke_test(&image, arch, cfg));
+		}
+
 		// Reduce storage overhead by removing the original chroot
 		// However, we'll keep an env flag to keep the chroot for debugging purposes
 		if env_flag!("KATSU_KEEP_CHROOT").is_none() {
@@ -885,6 +1929,69 @@ impl ImageBuilder for IsoBuilder {
 	}
 }
 
+/// Builds the root, then exports the kernel and initramfs for network booting
+pub struct PxeBuilder {
+	pub root_builder: Box<dyn RootBuilder>,
+}
+
+const PXE_VMLINUZ: &str = "vmlinuz";
+const PXE_INITRAMFS: &str = "initramfs.img";
+const PXE_ROOTIMG: &str = "squashfs.img";
+
+impl PxeBuilder {
+	/// Copies the kernel and initramfs from the chroot into `out`, then writes a `boot.ipxe` script
+	/// referencing them. If `rootimg` is set, the squashfs in `out` is passed as the live root.
+	pub fn export(chroot: &Path, out: &Path, manifest: &Manifest, rootimg: bool) -> Result<()> {
+		let bootdir = chroot.join("boot");
+		let (vmlinuz, initramfs) = find_vmlinuz_initramfs(chroot)?;
+
+		info!(?out, "Exporting kernel and initramfs");
+		fs::create_dir_all(out)?;
+		fs::copy(bootdir.join(vmlinuz), out.join(PXE_VMLINUZ))?;
+		fs::copy(bootdir.join(initramfs), out.join(PXE_INITRAMFS))?;
+
+		let cmd = manifest.kernel_cmdline.as_deref().unwrap_or_default();
+		let rootimg = if rootimg { PXE_ROOTIMG } else { "" };
+		crate::tpl!("ipxe.tera" => {
+			PREPEND: IPXE_PREPEND_COMMENT,
+			vmlinuz: PXE_VMLINUZ,
+			initramfs: PXE_INITRAMFS,
+			rootimg,
+			cmd
+		} => out.join("boot.ipxe"));
+
+		Ok(())
+	}
+}
+
+impl ImageBuilder for PxeBuilder {
+	fn build(
+		&self, chroot: &Path, _: &Path, manifest: &Manifest, skip_phases: &SkipPhases,
+	) -> Result<()> {
+		crate::gen_phase!(skip_phases);
+
+		let out = PathBuf::from(manifest.out_file.as_ref().map_or(DEFAULT_PXE_OUT, |s| s));
+		fs::create_dir_all(&out)?;
+
+		phase!("root": self.root_builder.build(chroot, manifest));
+
+		phase!("dracut": IsoBuilder::dracut(chroot, false));
+		manifest.dracut.cleanup(chroot)?;
+
+		phase!("rootimg": IsoBuilder::squashfs(chroot, &out.join(PXE_ROOTIMG), &manifest.compression(OutputFormat::Pxe)));
+
+		let rootimg = out.join(PXE_ROOTIMG).exists();
+		phase!("pxe": Self::export(chroot, &out, manifest, rootimg));
+
+		if env_flag!("KATSU_KEEP_CHROOT").is_none() {
+			info!("Removing chroot");
+			fs::remove_dir_all(chroot)?;
+		}
+
+		Ok(())
+	}
+}
+
 // todo: proper builder struct
 
 pub struct KatsuBuilder {
@@ -911,17 +2018,46 @@ impl KatsuBuilder {
 			OutputFormat::DiskImage => Box::new(DiskImageBuilder {
 				bootloader,
 				root_builder,
-				image: PathBuf::from("./katsu-work/image/katsu.img"),
+				image: PathBuf::from(DISK_IMAGE_OUT),
 			}) as Box<dyn ImageBuilder>,
 			OutputFormat::Folder => {
 				Box::new(FsBuilder { bootloader, root_builder }) as Box<dyn ImageBuilder>
 			},
+			OutputFormat::Pxe => Box::new(PxeBuilder { root_builder }) as Box<dyn ImageBuilder>,
 			_ => todo!(),
 		};
 
 		Ok(Self { image_builder, manifest, skip_phases })
 	}
 
+	/// Paths of the files produced for `output`
+	///
+	/// Folder outputs are left out since they are root filesystems with their own ownership.
+	pub fn artifacts(output: OutputFormat, manifest: &Manifest) -> Vec<PathBuf> {
+		let out_file =
+			|default: &str| PathBuf::from(manifest.out_file.as_deref().unwrap_or(default));
+		match output {
+			OutputFormat::Iso => {
+				let iso = out_file(DEFAULT_ISO_OUT);
+				let mut artifacts = vec![iso.clone()];
+				if manifest.iso.as_ref().is_some_and(|iso| iso.sign_key.is_some()) {
+					let mut sig = iso.into_os_string();
+					sig.push(".asc");
+					artifacts.push(sig.into());
+				}
+				artifacts
+			},
+			OutputFormat::DiskImage => {
+				let image = PathBuf::from(DISK_IMAGE_OUT);
+				let compressed = compressed_image(&image, &manifest.compression(output));
+				std::iter::once(image).chain(compressed).collect()
+			},
+			OutputFormat::Pxe => vec![out_file(DEFAULT_PXE_OUT)],
+			OutputFormat::Folder | OutputFormat::Device => vec![],
+		}
+	}
+
+	#[tracing::instrument(target = "katsu::builder", skip_all)]
 	pub fn build(&self) -> Result<()> {
 		let workdir = PathBuf::from(WORKDIR);
 
@@ -934,3 +2070,555 @@ impl KatsuBuilder {
 		self.image_builder.build(&chroot, &image, &self.manifest, &self.skip_phases)
 	}
 }
+
+#[test]
+fn test_grub_aarch64_chroot_paths() {
+	let req = Bootloader::Grub.requirements("aarch64", &IsoConfig::default()).unwrap();
+	assert_eq!(req.chroot_paths, vec!["usr/lib/grub/arm64-efi"]);
+	assert_eq!(req.efi_bins, vec!["shimaa64.efi", "grubaa64.efi"]);
+
+	assert!(Bootloader::Grub.requirements("riscv64", &IsoConfig::default()).is_err());
+}
+
+#[test]
+fn test_limine_requirements() {
+	let req = Bootloader::Limine.requirements("x86_64", &IsoConfig::default()).unwrap();
+	assert!(req.host_bins.contains(&"limine"));
+	assert_eq!(
+		req.host_files,
+		[
+			"/usr/share/limine/limine-uefi-cd.bin",
+			"/usr/share/limine/limine-bios-cd.bin",
+			"/usr/share/limine/limine-bios.sys",
+		]
+		.map(PathBuf::from)
+	);
+	assert!(req.chroot_paths.is_empty() && req.efi_bins.is_empty());
+}
+
+#[test]
+fn test_pxe_export() {
+	let tmp = crate::util::TestDir::new("pxe");
+	let (chroot, out) = (tmp.join("chroot"), tmp.join("out"));
+	fs::create_dir_all(chroot.join("boot")).unwrap();
+	fs::write(chroot.join("boot/vmlinuz-6.8.0"), "kernel").unwrap();
+	fs::write(chroot.join("boot/initramfs-6.8.0.img"), "initramfs").unwrap();
+
+	let manifest: Manifest = serde_yaml::from_str("builder: dnf\nkernel_cmdline: quiet\n").unwrap();
+	PxeBuilder::export(&chroot, &out, &manifest, true).unwrap();
+
+	assert_eq!(fs::read_to_string(out.join(PXE_VMLINUZ)).unwrap(), "kernel");
+	assert_eq!(fs::read_to_string(out.join(PXE_INITRAMFS)).unwrap(), "initramfs");
+
+	let script = fs::read_to_string(out.join("boot.ipxe")).unwrap();
+	assert!(script.starts_with("#!ipxe\n"));
+	assert!(script.contains(
+		"kernel vmlinuz root=live:${cwduri}squashfs.img rd.live.image quiet\ninitrd initramfs.img\n"
+	));
+}
+
+#[test]
+fn test_artifacts() {
+	let manifest: Manifest =
+		serde_yaml::from_str("builder: dnf\nout_file: out.iso\niso:\n  sign_key: DEADBEEF\n")
+			.unwrap();
+	assert_eq!(
+		KatsuBuilder::artifacts(OutputFormat::Iso, &manifest),
+		[PathBuf::from("out.iso"), PathBuf::from("out.iso.asc")]
+	);
+
+	// the compressed copy is opt-in
+	let manifest: Manifest = serde_yaml::from_str("builder: dnf\n").unwrap();
+	assert_eq!(
+		KatsuBuilder::artifacts(OutputFormat::DiskImage, &manifest),
+		[PathBuf::from(DISK_IMAGE_OUT)]
+	);
+	let manifest: Manifest =
+		serde_yaml::from_str("disk:\n  compression:\n    algorithm: xz\n  partitions: []\n")
+			.unwrap();
+	assert_eq!(
+		KatsuBuilder::artifacts(OutputFormat::DiskImage, &manifest),
+		[PathBuf::from(DISK_IMAGE_OUT), PathBuf::from(format!("{DISK_IMAGE_OUT}.xz"))]
+	);
+	assert!(KatsuBuilder::artifacts(OutputFormat::Folder, &manifest).is_empty());
+}
+
+#[test]
+fn test_grub_cfg_boot_modes() {
+	let manifest: Manifest = serde_yaml::from_str(
+		"builder: dnf\nkernel_cmdline: quiet\nkernel_cmdline_uefi: quiet efifb=on\n",
+	)
+	.unwrap();
+	let render = |mode| {
+		Bootloader::Grub
+			.grub_cfg(&manifest, "vmlinuz-6.8.0", &["initramfs-6.8.0.img".to_string()], mode)
+			.unwrap()
+	};
+
+	let bios = render(BootMode::Bios);
+	assert!(bios.contains("rd.live.image enforcing=0 quiet\n"));
+	assert!(!bios.contains("efifb"));
+
+	let uefi = render(BootMode::Uefi);
+	assert!(uefi.contains("rd.live.image enforcing=0 quiet efifb=on\n"));
+}
+
+#[test]
+fn test_cp_grub_dir_fallback() {
+	let tmp = crate::util::TestDir::new("grubdir");
+	let (chroot, imgd) = (tmp.join("chroot"), tmp.join("iso-tree"));
+	fs::create_dir_all(chroot.join("usr/lib/grub/x86_64-efi")).unwrap();
+	fs::create_dir_all(chroot.join("usr/share/grub")).unwrap();
+	fs::create_dir_all(imgd.join("boot")).unwrap();
+	fs::write(chroot.join("usr/lib/grub/x86_64-efi/normal.mod"), "").unwrap();
+	fs::write(chroot.join("usr/share/grub/unicode.pf2"), "").unwrap();
+
+	Bootloader::cp_grub_dir(&chroot, &imgd).unwrap();
+
+	assert!(imgd.join("boot/grub/x86_64-efi/normal.mod").is_file());
+	assert!(imgd.join("boot/grub/fonts/unicode.pf2").is_file());
+}
+
+#[test]
+fn test_grub_xorrisofs_gpt_only() {
+	let has_mbr = |yaml: &str| {
+		let manifest: Manifest = serde_yaml::from_str(yaml).unwrap();
+		let cmd = IsoBuilder::grub_xorrisofs_cmd(
+			Path::new("katsu-work/chroot"),
+			Path::new("out.iso"),
+			&manifest,
+		);
+		cmd.get_args().any(|a| a == "--grub2-mbr")
+	};
+
+	assert!(has_mbr("builder: dnf\ndnf:\n  arch: x86_64\n"));
+	assert!(!has_mbr("builder: dnf\ndnf:\n  arch: x86_64\niso:\n  gpt_only: true\n"));
+}
+
+#[test]
+fn test_grub_xorrisofs_aarch64() {
+	let args = |arch: &str| {
+		let manifest: Manifest =
+			serde_yaml::from_str(&format!("builder: dnf\ndnf:\n  arch: {arch}\n")).unwrap();
+		let cmd = IsoBuilder::grub_xorrisofs_cmd(
+			Path::new("katsu-work/chroot"),
+			Path::new("out.iso"),
+			&manifest,
+		);
+		cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect::<Vec<_>>()
+	};
+
+	let aarch64 = args("aarch64");
+	let efi = aarch64.iter().position(|a| a == "-e").unwrap();
+	assert_eq!(aarch64[efi + 1], "--interval:appended_partition_2:all::");
+	assert!(aarch64.iter().any(|a| a == "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"));
+	assert!(!aarch64.iter().any(|a| a == "-b" || a == "-eltorito-alt-boot"));
+
+	assert!(args("x86_64").iter().any(|a| a == "-b"));
+}
+
+#[test]
+fn test_custom_rootfs_path() {
+	let manifest: Manifest =
+		serde_yaml::from_str("builder: dnf\niso:\n  live_dir: live\n  rootfs_name: rootfs.img\n")
+			.unwrap();
+	assert_eq!(manifest.get_rootfs_path(), Path::new("live/rootfs.img"));
+
+	let cfg = Bootloader::Grub
+		.grub_cfg(&manifest, "vmlinuz-6.8.0", &["initramfs-6.8.0.img".to_string()], BootMode::Uefi)
+		.unwrap();
+	assert!(cfg.contains("rd.live.image rd.live.dir=live rd.live.squashimg=rootfs.img enforcing=0"));
+
+	// defaults don't need any extra arguments
+	let manifest: Manifest = serde_yaml::from_str("builder: dnf\n").unwrap();
+	assert_eq!(manifest.get_rootfs_path(), Path::new("LiveOS/squashfs.img"));
+	assert_eq!(manifest.get_live_args(), "");
+}
+
+#[test]
+fn test_bootupd_efi_files() {
+	let tmp = crate::util::TestDir::new("bootupd");
+	let (chroot, imgd) = (tmp.join("chroot"), tmp.join("iso-tree"));
+	let updates = chroot.join("usr/lib/bootupd/updates/EFI");
+	fs::create_dir_all(updates.join("BOOT")).unwrap();
+	fs::create_dir_all(updates.join("fedora")).unwrap();
+	fs::create_dir_all(chroot.join("usr/bin")).unwrap();
+	fs::write(updates.join("BOOT/BOOTX64.EFI"), "").unwrap();
+	fs::write(updates.join("fedora/shimx64.efi"), "shim").unwrap();
+
+	// without bootupctl the ESP is used
+	assert_eq!(efi_dir(&chroot), chroot.join("boot/efi/EFI/fedora"));
+
+	fs::write(chroot.join("usr/bin/bootupctl"), "").unwrap();
+	assert_eq!(efi_dir(&chroot), updates.join("fedora"));
+	let err = Bootloader::Grub.check_chroot(&chroot, "aarch64", &IsoConfig::default()).unwrap_err();
+	assert!(err.to_string().contains("usr/lib/bootupd/updates/EFI/fedora/shimaa64.efi"));
+
+	cp_efi_files(&chroot, &imgd).unwrap();
+	assert_eq!(fs::read_to_string(imgd.join("EFI/BOOT/shimx64.efi")).unwrap(), "shim");
+}
+
+#[test]
+fn test_syslinux_cfg() {
+	let manifest: Manifest = serde_yaml::from_str(
+		"builder: dnf\ndistro: Ultramarine Linux\nkernel_cmdline: quiet\niso:\n  volume_id: ULTRAMARINE\n",
+	)
+	.unwrap();
+	let cfg =
+		Bootloader::syslinux_cfg(&manifest, "vmlinuz-6.8.0", &["initramfs-6.8.0.img".to_string()])
+			.unwrap();
+	assert!(cfg.contains("KERNEL /boot/vmlinuz-6.8.0"));
+	assert!(cfg.contains(
+		"APPEND initrd=/boot/initramfs-6.8.0.img root=live:CDLABEL=ULTRAMARINE rd.live.image enforcing=0 quiet"
+	));
+	assert_eq!(Bootloader::from("isolinux"), Bootloader::Syslinux);
+	assert!(Bootloader::Syslinux.requirements("aarch64", &IsoConfig::default()).is_err());
+}
+
+#[test]
+fn test_script_ignore_errors() {
+	fn script<'a>(id: &'a str, ignore_errors: bool, needs: &[&str]) -> (&'a str, (Script, bool)) {
+		let script = Script {
+			id: Some(id.to_string()),
+			ignore_errors,
+			needs: needs.iter().map(|n| n.to_string()).collect(),
+			..Default::default()
+		};
+		(id, (script, false))
+	}
+	let ran = std::cell::RefCell::new(vec![]);
+	let run = |script: Script| -> Result<()> {
+		let id = script.id.unwrap();
+		ran.borrow_mut().push(id.clone());
+		if id.starts_with("fails") {
+			bail!("{id} failed");
+		}
+		Ok(())
+	};
+
+	let scripts = [script("runs", false, &["fails"]), script("fails", true, &[])];
+	run_scripts_with(scripts.into_iter().collect(), &run).unwrap();
+	assert_eq!(*ran.borrow(), ["fails", "runs"]);
+
+	ran.borrow_mut().clear();
+	let scripts = [script("fails", false, &[]), script("runs", false, &[])];
+	let err = run_scripts_with(scripts.into_iter().collect(), &run).unwrap_err();
+	assert_eq!(err.to_string(), "fails failed");
+	assert_eq!(*ran.borrow(), ["fails"]);
+}
+
+#[test]
+fn test_efi_bin_overrides() {
+	let tmp = crate::util::TestDir::new("efi-bins");
+	let boot = tmp.join("EFI/BOOT");
+	fs::create_dir_all(&boot).unwrap();
+	fs::write(boot.join("shimx64-signed.efi"), "shim").unwrap();
+	fs::write(boot.join("grubx64-signed.efi"), "grub").unwrap();
+
+	let iso: IsoConfig = serde_yaml::from_str(
+		"efi_bins:\n  x86_64:\n    shim: shimx64-signed.efi\n    grub: grubx64-signed.efi\n",
+	)
+	.unwrap();
+	assert_eq!(iso.shim_name("aarch64").unwrap(), "shimaa64.efi");
+	assert_eq!(
+		Bootloader::Grub.requirements("x86_64", &iso).unwrap().efi_bins,
+		vec!["shimx64-signed.efi", "grubx64-signed.efi"]
+	);
+
+	cp_efi_boot_bins(&tmp, "x86_64", &iso).unwrap();
+	assert_eq!(fs::read_to_string(boot.join("BOOTX64.efi")).unwrap(), "shim");
+	assert_eq!(fs::read_to_string(boot.join("grubx64.efi")).unwrap(), "grub");
+}
+
+#[test]
+fn test_iso_root_files() {
+	let tmp = crate::util::TestDir::new("root-files");
+	let tree = tmp.join(ISO_TREE);
+	fs::create_dir_all(tmp.join("docs")).unwrap();
+	fs::write(tmp.join("docs/README.txt"), "Welcome to Ultramarine").unwrap();
+	fs::write(tmp.join("LICENSE"), "MIT").unwrap();
+	fs::write(
+		tmp.join("katsu.yaml"),
+		"builder: dnf\niso:\n  root_files:\n    - source: docs/README.txt\n    - source: LICENSE\n      dest: licenses/LICENSE.txt\n",
+	)
+	.unwrap();
+
+	let manifest = Manifest::load(&tmp.join("katsu.yaml"), &[]).unwrap();
+	cp_iso_root_files(&manifest, &tree).unwrap();
+	assert_eq!(fs::read_to_string(tree.join("README.txt")).unwrap(), "Welcome to Ultramarine");
+	assert_eq!(fs::read_to_string(tree.join("licenses/LICENSE.txt")).unwrap(), "MIT");
+
+	let escape: crate::config::IsoRootFile =
+		serde_yaml::from_str("source: LICENSE\ndest: ../LICENSE\n").unwrap();
+	assert!(escape.dest_in(&tree).is_err());
+}
+
+#[test]
+fn test_smoke_test_cmd() {
+	let cfg = SmokeTestConfig::default();
+	let cmd = smoke_test_cmd(Path::new("out.iso"), "x86_64", &cfg).unwrap();
+	assert_eq!(cmd.get_program(), "qemu-system-x86_64");
+	let args: Vec<_> = cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+	let has = |flag: &str, value: &str| args.windows(2).any(|w| w[0] == flag && w[1] == value);
+	let firmware = cfg.get_firmware("x86_64").unwrap();
+	assert!(has("-machine", "q35,accel=kvm:tcg"));
+	assert!(has(
+		"-drive",
+		&format!("if=pflash,format=raw,readonly=on,file={}", firmware.display())
+	));
+	assert!(has("-cdrom", "out.iso"));
+	assert!(has("-serial", "stdio"));
+	assert_eq!(cfg.get_marker(), "login:");
+	assert!(smoke_test_cmd(Path::new("out.iso"), "riscv64", &cfg).is_err());
+}
+
+#[test]
+fn test_koji_repo_options() {
+	let dnf: DnfRootBuilder = serde_yaml::from_str(
+		"koji_tags: [terra40]\nkoji_url: https://koji.example.com/kojifiles/\n",
+	)
+	.unwrap();
+	assert_eq!(
+		dnf.koji_repo_options("aarch64")[0],
+		"--repofrompath=katsu-koji-terra40,https://koji.example.com/kojifiles/repos/terra40/latest/aarch64/"
+	);
+}
+
+#[test]
+fn test_grub_cfg_search_label() {
+	let manifest: Manifest =
+		serde_yaml::from_str("builder: dnf\niso:\n  volume_id: ULTRAMARINE-40\n").unwrap();
+	let cfg = Bootloader::Grub
+		.grub_cfg(&manifest, "vmlinuz", &["initramfs.img".to_string()], BootMode::Uefi)
+		.unwrap();
+	let stanza = cfg.lines().find(|l| !l.is_empty() && !l.starts_with('#'));
+	assert_eq!(stanza, Some("search --no-floppy --set=root --label 'ULTRAMARINE-40'"));
+}
+
+#[test]
+fn test_dracut_hostonly() {
+	let generic = IsoBuilder::dracut_args(false);
+	assert!(generic.iter().any(|a| a == "-fN"));
+	assert!(!generic.iter().any(|a| a == "-fH"));
+
+	let hostonly = IsoBuilder::dracut_args(true);
+	assert!(hostonly.iter().any(|a| a == "-fH"));
+	assert!(!hostonly.iter().any(|a| a == "-fN"));
+}
+
+#[test]
+fn test_copy_files_stages() {
+	let pos = |step| ROOT_STEPS.iter().position(|s| *s == step).unwrap();
+	let after_install = pos(RootStep::CopyFiles(CopyStage::AfterInstall));
+	assert!(pos(RootStep::CopyFiles(CopyStage::Pre)) < pos(RootStep::Install));
+	assert!(pos(RootStep::Install) < after_install);
+	assert!(after_install < pos(RootStep::PostScripts));
+	assert!(pos(RootStep::PostScripts) < pos(RootStep::CopyFiles(CopyStage::Post)));
+
+	let tmp = crate::util::TestDir::new("copy-files");
+	let chroot = tmp.join("chroot");
+	fs::create_dir_all(&chroot).unwrap();
+	fs::write(tmp.join("terra.repo"), "[terra]").unwrap();
+	fs::write(tmp.join("99-custom.conf"), "vm.swappiness = 10").unwrap();
+	let manifest: Manifest = serde_yaml::from_str(&format!(
+		"copy_files:\n  - source: {0}/terra.repo\n    dest: /etc/yum.repos.d/terra.repo\n  - source: {0}/99-custom.conf\n    dest: /etc/sysctl.d/99-custom.conf\n    stage: after-install\n",
+		tmp.display()
+	))
+	.unwrap();
+
+	manifest.copy_files_at(&chroot, CopyStage::Pre).unwrap();
+	assert!(chroot.join("etc/yum.repos.d/terra.repo").is_file());
+	assert!(!chroot.join("etc/sysctl.d/99-custom.conf").exists());
+	manifest.copy_files_at(&chroot, CopyStage::AfterInstall).unwrap();
+	assert!(chroot.join("etc/sysctl.d/99-custom.conf").is_file());
+}
+
+#[test]
+fn test_strip_rpmdb() {
+	let tmp = crate::util::TestDir::new("strip-rpmdb");
+	for dir in RPMDB_DIRS {
+		fs::create_dir_all(tmp.join(dir)).unwrap();
+	}
+	fs::write(tmp.join("usr/lib/sysimage/rpm/rpmdb.sqlite"), "").unwrap();
+
+	strip_rpmdb(&tmp, |chroot| {
+		// the list has to be made while the database is still there
+		assert!(chroot.join("usr/lib/sysimage/rpm/rpmdb.sqlite").exists());
+		Ok("bash-5.2.26-3.fc40.x86_64\nkernel-6.8.5-301.fc40.x86_64\n".to_string())
+	})
+	.unwrap();
+
+	assert!(RPMDB_DIRS.iter().all(|dir| !tmp.join(dir).exists()));
+	assert_eq!(
+		fs::read_to_string(tmp.join(PACKAGE_LIST)).unwrap(),
+		"bash-5.2.26-3.fc40.x86_64\nkernel-6.8.5-301.fc40.x86_64\n"
+	);
+}
+
+#[test]
+fn test_grub_cfg_initrds() {
+	let manifest: Manifest =
+		serde_yaml::from_str("builder: dnf\niso:\n  early_initrds: [microcode.img]\n").unwrap();
+	let initrds = manifest.iso.as_ref().unwrap().initrds("initramfs-6.8.0.img");
+	assert_eq!(initrds, ["microcode.img", "initramfs-6.8.0.img"]);
+
+	let cfg =
+		Bootloader::Grub.grub_cfg(&manifest, "vmlinuz-6.8.0", &initrds, BootMode::Uefi).unwrap();
+	assert!(cfg.contains("\tinitrd /boot/microcode.img /boot/initramfs-6.8.0.img\n"));
+	assert!(!cfg.contains("\tinitrd /boot/initramfs-6.8.0.img\n"));
+
+	let cfg = Bootloader::syslinux_cfg(&manifest, "vmlinuz-6.8.0", &initrds).unwrap();
+	assert!(cfg.contains("APPEND initrd=/boot/microcode.img,/boot/initramfs-6.8.0.img "));
+}
+
+#[test]
+fn test_extlinux_conf() {
+	let manifest: Manifest = serde_yaml::from_str(
+		"distro: Ultramarine\nkernel_cmdline: root=PARTUUID=1234 rw\ndisk:\n  extlinux:\n    fdt: dtb/broadcom/bcm2711-rpi-4-b.dtb\n  partitions: []\n",
+	)
+	.unwrap();
+	let extlinux = manifest.disk.as_ref().unwrap().extlinux_config().unwrap();
+	let conf = extlinux_conf(&manifest, &extlinux, "vmlinuz-6.8.0", "initramfs-6.8.0.img").unwrap();
+	assert!(conf.contains(
+		"LABEL Ultramarine\n\tLINUX /boot/vmlinuz-6.8.0\n\tINITRD /boot/initramfs-6.8.0.img\n\tFDT /boot/dtb/broadcom/bcm2711-rpi-4-b.dtb\n\tAPPEND root=PARTUUID=1234 rw\n"
+	));
+
+	let conf =
+		extlinux_conf(&manifest, &Default::default(), "vmlinuz-6.8.0", "initramfs-6.8.0.img")
+			.unwrap();
+	assert!(conf.contains("\tINITRD /boot/initramfs-6.8.0.img\n\tAPPEND "));
+}
+
+#[test]
+fn test_cp_dtbs() {
+	let tmp = crate::util::TestDir::new("dtbs");
+	let dtb_dir = tmp.join("usr/lib/modules/6.8.0/dtb/broadcom");
+	fs::create_dir_all(&dtb_dir).unwrap();
+	fs::write(dtb_dir.join("bcm2711-rpi-4-b.dtb"), "rpi4").unwrap();
+	fs::write(dtb_dir.join("bcm2837-rpi-3-b.dtb"), "rpi3").unwrap();
+
+	let manifest: Manifest = serde_yaml::from_str(
+		"disk:\n  uboot:\n    board: rpi_4\n  dtbs: [broadcom/bcm2711-rpi-4-b.dtb]\n  partitions: []\n",
+	)
+	.unwrap();
+	let disk = manifest.disk.as_ref().unwrap();
+	cp_dtbs(&tmp, disk, "6.8.0").unwrap();
+	assert_eq!(
+		fs::read_to_string(tmp.join("boot/dtb/broadcom/bcm2711-rpi-4-b.dtb")).unwrap(),
+		"rpi4"
+	);
+	assert!(tmp.join("boot/efi/bcm2711-rpi-4-b.dtb").is_file());
+	assert!(!tmp.join("boot/dtb/broadcom/bcm2837-rpi-3-b.dtb").exists());
+
+	let extlinux = disk.extlinux_config().unwrap();
+	let conf = extlinux_conf(&manifest, &extlinux, "vmlinuz-6.8.0", "initramfs-6.8.0.img").unwrap();
+	assert!(conf.contains("\tFDT /boot/dtb/broadcom/bcm2711-rpi-4-b.dtb\n"));
+}
+
+#[test]
+fn test_grub_cfg_timeout_style() {
+	let cfg = |yaml: &str| {
+		let manifest: Manifest = serde_yaml::from_str(yaml).unwrap();
+		let initrds = ["initramfs.img".to_string()];
+		Bootloader::Grub.grub_cfg(&manifest, "vmlinuz", &initrds, BootMode::Uefi).unwrap()
+	};
+
+	let hidden = cfg("builder: dnf\niso:\n  grub_timeout: 2\n  grub_timeout_style: hidden\n");
+	assert!(hidden.contains(
+		"insmod chain\n# hold shift to show the menu\nif keystatus --shift; then\n\tset timeout=-1\nelse\n\tset timeout_style=hidden\n\tset timeout=2\nfi\n"
+	));
+
+	let default = cfg("builder: dnf\n");
+	assert!(default.contains("insmod chain\nset timeout=60\n"));
+	assert!(!default.contains("timeout_style"));
+
+	let countdown = cfg("builder: dnf\niso:\n  grub_timeout_style: countdown\n");
+	assert!(countdown.contains("insmod chain\nset timeout_style=countdown\nset timeout=60\n"));
+}
+
+#[test]
+fn test_bls_entries() {
+	let tmp = crate::util::TestDir::new("bls");
+	let (chroot, tree) = (tmp.join("chroot"), tmp.join("tree"));
+	fs::create_dir_all(chroot.join(BLS_ENTRIES)).unwrap();
+	fs::write(
+		chroot.join(BLS_ENTRIES).join("abc-6.8.0.conf"),
+		"title Ultramarine (6.8.0)\nversion 6.8.0\nlinux /vmlinuz-6.8.0\ninitrd /initramfs-6.8.0.img\noptions root=UUID=1234 ro\ngrub_users $grub_users\n",
+	)
+	.unwrap();
+
+	let manifest: Manifest = serde_yaml::from_str(
+		"builder: dnf\nkernel_cmdline: quiet\niso:\n  volume_id: ULTRAMARINE\n  bls: true\n",
+	)
+	.unwrap();
+	cp_bls_entries(&chroot, &tree, &live_bls_options(&manifest)).unwrap();
+	assert_eq!(
+		fs::read_to_string(tree.join(BLS_ENTRIES).join("abc-6.8.0.conf")).unwrap(),
+		"title Ultramarine (6.8.0)\nversion 6.8.0\nlinux /boot/vmlinuz-6.8.0\ninitrd /boot/initramfs-6.8.0.img\noptions root=live:CDLABEL=ULTRAMARINE rd.live.image enforcing=0 quiet\ngrub_users $grub_users\n"
+	);
+
+	let initrds = ["initramfs-6.8.0.img".to_string()];
+	let cfg =
+		Bootloader::Grub.grub_cfg(&manifest, "vmlinuz-6.8.0", &initrds, BootMode::Uefi).unwrap();
+	assert!(cfg.ends_with("insmod blscfg\nblscfg\n"));
+}
+
+#[test]
+fn test_grub_efi_mkimage_cmd() {
+	let args = |arch| -> Result<Vec<String>> {
+		let cmd = grub_efi_mkimage_cmd(Path::new("/chroot"), arch, Path::new("/out.efi"))?;
+		Ok(cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect())
+	};
+	assert!(args("x86_64").unwrap().iter().any(|a| a == "efi_uga"));
+	let aarch64 = args("aarch64").unwrap();
+	assert_eq!(aarch64[1], "arm64-efi");
+	assert!(!aarch64.iter().any(|a| a == "efi_uga"));
+	assert!(args("riscv64").is_err());
+}
+
+#[test]
+fn test_compress_cmd() {
+	let image = Path::new("/work/katsu.img");
+	let xz =
+		Compression { algorithm: Compressor::Xz, level: None }.resolve(OutputFormat::DiskImage);
+	assert_eq!(compressed_image(image, &xz), Some(PathBuf::from("/work/katsu.img.xz")));
+
+	let none = Compression::default_for(OutputFormat::Folder);
+	assert!(compress_cmd(image, &none).unwrap().is_none());
+	assert!(compressed_image(image, &none).is_none());
+
+	let lz4 = Compression { algorithm: Compressor::Lz4, level: None };
+	let err = compress_cmd(image, &lz4).unwrap_err();
+	assert_eq!(err.to_string(), "Disk images can't be compressed with Lz4");
+}
+
+#[test]
+fn test_systemd_setup_cmds() {
+	let chroot = Path::new("/work/chroot");
+	let manifest: Manifest = serde_yaml::from_str("sysusers: true\n").unwrap();
+	let (sysusers, tmpfiles) = systemd_setup_cmds(chroot, &manifest);
+	assert!(sysusers.is_some() && tmpfiles.is_none());
+
+	let manifest: Manifest = serde_yaml::from_str("builder: dnf\n").unwrap();
+	let (sysusers, tmpfiles) = systemd_setup_cmds(chroot, &manifest);
+	assert!(sysusers.is_none() && tmpfiles.is_none());
+}
+
+#[test]
+fn test_grub_cfg_configfile() {
+	let manifest: Manifest = serde_yaml::from_str(
+		"builder: dnf\niso:\n  volume_id: ULTRAMARINE\n  grub_configfile: boot/grub2/grub.cfg\n",
+	)
+	.unwrap();
+	let initrds = ["initramfs-6.8.0.img".to_string()];
+	let cfg =
+		Bootloader::Grub.grub_cfg(&manifest, "vmlinuz-6.8.0", &initrds, BootMode::Uefi).unwrap();
+	assert!(cfg.contains(
+		"loopback rootfs /LiveOS/squashfs.img\nset root=(rootfs)\nconfigfile (rootfs)/boot/grub2/grub.cfg\n"
+	));
+
+	let manifest: Manifest = serde_yaml::from_str("builder: dnf\n").unwrap();
+	let cfg =
+		Bootloader::Grub.grub_cfg(&manifest, "vmlinuz-6.8.0", &initrds, BootMode::Uefi).unwrap();
+	assert!(!cfg.contains("configfile"));
+}
@@ -1,17 +1,24 @@
 use std::path::PathBuf;
 
 use clap::{value_parser, Parser, ValueEnum};
-use color_eyre::Result;
+use color_eyre::{eyre::WrapErr, Result};
 use serde_derive::{Deserialize, Serialize};
 use tracing::trace;
 
-use crate::{builder::KatsuBuilder, config::Manifest};
+use crate::{
+	bail_let,
+	builder::KatsuBuilder,
+	config::{Compression, Compressor, Manifest},
+};
 
 // The structure should be like RPM-OSTree's Compose
 // CLI
 // so we can do something like
 // katsu compose /path/to/manifest.yaml
 
+/// Where `--embed-manifest` puts the manifest in the image
+const DEFAULT_EMBED_MANIFEST: &str = "/usr/lib/katsu/manifest.json";
+
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct KatsuCli {
@@ -22,10 +29,10 @@ pub struct KatsuCli {
 	/// Config file location
 	config: Option<PathBuf>,
 
-	#[arg(short, long)]
+	#[arg(short, long, required_unless_present = "inspect")]
 	#[arg(value_enum)]
 	/// Format of the artifact Katsu should output
-	output: OutputFormat,
+	output: Option<OutputFormat>,
 	
 	/// Skip individual phases
 	/// 
@@ -45,6 +52,115 @@ pub struct KatsuCli {
 	#[arg(long, short = 'O')]
 	/// Override output file location
 	output_file: Option<PathBuf>,
+
+	#[arg(long = "include-dir", short = 'I')]
+	/// Extra directories to search for imports and scripts, can be specified multiple times
+	///
+	/// Paths are always looked up relative to the manifest first
+	include_dirs: Vec<PathBuf>,
+
+	#[arg(long, value_name = "KEYID")]
+	/// Sign the ISO with the given GPG key, producing a detached ASCII-armored signature
+	sign_iso: Option<String>,
+
+	#[arg(long, value_name = "ALGORITHM")]
+	/// Compression of the root filesystem image of ISO and PXE outputs, overriding
+	/// `compression.algorithm` in the manifest. `none` (or `store`) leaves it uncompressed for
+	/// the fastest boots. Disk images use `disk.compression` instead
+	rootfs_compression: Option<Compressor>,
+
+	#[arg(long)]
+	/// Fail if the ISO is larger than `iso.media_size` in the manifest, instead of warning
+	fail_if_larger_than_media: bool,
+
+	#[arg(long)]
+	/// Print the partition table that would be created for the disk layout, then exit without
+	/// building or touching any disk
+	plan: bool,
+
+	#[arg(long, value_name = "ARTIFACT", exclusive = true)]
+	/// Print information about a built ISO, disk image or filesystem image, then exit
+	///
+	/// Reports the volume ID, El Torito boot entries, implanted MD5 and root filesystem
+	/// compression of ISOs, and the partitions and filesystems of disk images
+	inspect: Option<PathBuf>,
+
+	#[arg(long)]
+	/// Boot the built ISO in QEMU and fail if it doesn't reach a login prompt
+	///
+	/// See `iso.smoke_test` in the manifest for options
+	smoke_test: bool,
+
+	#[arg(long = "repo-from-koji", value_name = "TAG")]
+	/// Enable a Koji tag as an extra repo while installing packages, can be specified multiple
+	/// times
+	///
+	/// Use the side tag of a Bodhi update to test its builds
+	koji_tags: Vec<String>,
+
+	#[arg(long, value_name = "DIR")]
+	/// Install packages only from a local directory of RPMs, without network access
+	offline: Option<PathBuf>,
+
+	#[arg(long)]
+	/// Preseed `/var/lib/systemd/random-seed` in the image from the host, see `random_seed` in
+	/// the manifest. Makes the build unreproducible
+	seed_rng: bool,
+
+	#[arg(long = "strip-rpm-db")]
+	/// Remove the RPM database from the image after recording the package list, see
+	/// `strip_rpmdb` in the manifest
+	strip_rpmdb: bool,
+
+	#[arg(long)]
+	/// Embed the resolved manifest in the image at `/usr/lib/katsu/manifest.json`, unless
+	/// `embed_manifest` sets another path
+	embed_manifest: bool,
+
+	#[arg(long)]
+	/// For folder output, record ownership and permissions to `<out>.pf` for `mksquashfs -pf`
+	preserve_permissions: bool,
+
+	#[arg(long)]
+	/// Write a JSON summary of the partitions created for disk images to this path
+	summary_json: Option<PathBuf>,
+
+	#[arg(long, env = "KATSU_ROOTLESS")]
+	/// (Experimental) Build inside a user namespace instead of escalating with sudo
+	///
+	/// Only supported for folder output, other formats still escalate with sudo
+	rootless: bool,
+
+	#[arg(long)]
+	/// Skip the build if the output exists and was built from the same manifest, recording a
+	/// hash of the resolved manifest and the files it references in `<output>.katsu-hash`
+	since_manifest_hash: bool,
+
+	#[arg(long)]
+	/// Keep building when a pre or post script fails, as if every script had `ignore_errors` set
+	continue_on_script_error: bool,
+
+	#[arg(long, value_name = "UID:GID")]
+	/// Owner to give the output artifacts after the build
+	///
+	/// Defaults to the user that invoked sudo, if any
+	output_owner: Option<String>,
+
+	#[arg(long = "max-log-level-per-module", value_name = "MODULE=LEVEL")]
+	/// Override the log level of one subsystem, can be specified multiple times
+	///
+	/// Modules are `builder`, `bootloader`, `partition`, `dnf` and `dracut`, for example
+	/// `--max-log-level-per-module partition=trace`
+	pub log_modules: Vec<String>,
+
+	#[arg(long)]
+	/// Load and validate the manifest and its imports, then exit without building or checking
+	/// the host
+	check_config_only: bool,
+
+	#[arg(long, env = "KATSU_FAIL_ON_WARNING")]
+	/// Fail the build if any warnings were emitted
+	pub fail_on_warning: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -73,6 +189,51 @@ pub enum OutputFormat {
 	Device,
 	/// Simply copies the root tree to a directory
 	Folder,
+	/// Exports the kernel, initramfs and root image with an iPXE script for network booting
+	#[value(alias = "netboot")]
+	Pxe,
+}
+
+impl OutputFormat {
+	/// Whether this format can be built inside an unprivileged user namespace
+	///
+	/// Formats that need loop devices or real block devices always need root.
+	pub fn supports_rootless(self) -> bool {
+		matches!(self, OutputFormat::Folder)
+	}
+}
+
+/// How Katsu gets the privileges it needs for a build
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Privilege {
+	/// Re-run as root through `sudo`
+	Sudo,
+	/// Re-run as a mapped root user inside a user namespace
+	Rootless,
+}
+
+impl Privilege {
+	pub fn for_output(output: OutputFormat, rootless: bool) -> Self {
+		if !rootless {
+			return Privilege::Sudo;
+		}
+		if output.supports_rootless() {
+			Privilege::Rootless
+		} else {
+			tracing::warn!(?output, "Output format does not support rootless builds, falling back to sudo");
+			Privilege::Sudo
+		}
+	}
+
+	pub fn acquire(self) -> Result<()> {
+		match self {
+			Privilege::Sudo => {
+				sudo::with_env(&["KATSU_LOG"]).unwrap();
+				Ok(())
+			},
+			Privilege::Rootless => crate::util::enter_userns(),
+		}
+	}
 }
 
 impl std::str::FromStr for OutputFormat {
@@ -84,6 +245,7 @@ impl std::str::FromStr for OutputFormat {
 			"device" => Ok(OutputFormat::Device),
 			"folder" => Ok(OutputFormat::Folder),
 			"fs" => Ok(OutputFormat::Folder),
+			"pxe" | "netboot" => Ok(OutputFormat::Pxe),
 			_ => Err(format!("{s} is not a valid output format")),
 		}
 	}
@@ -100,11 +262,37 @@ impl std::str::FromStr for OutputFormat {
 /// - Failed to build image
 #[tracing::instrument]
 pub fn parse(cli: KatsuCli) -> Result<()> {
+	if let Some(artifact) = cli.inspect {
+		print!("{}", crate::inspect::inspect(&artifact)?);
+		return Ok(());
+	}
+
+	bail_let!(Some(output) = cli.output => "No output format specified");
+
 	// load manifest from config file
 
-	sudo::with_env(&["KATSU_LOG"]).unwrap();
+	if cli.check_config_only {
+		bail_let!(Some(config) = &cli.config => "No manifest to check");
+		let manifest = Manifest::load_all(config, output, &cli.include_dirs)
+			.wrap_err_with(|| format!("Invalid manifest {}", config.display()))?;
+		manifest.validate(output)?;
+		println!("{}: OK", config.display());
+		return Ok(());
+	}
+
+	if cli.plan {
+		bail_let!(Some(config) = &cli.config => "No manifest to plan");
+		let manifest = Manifest::load_all(config, output, &cli.include_dirs)?;
+		bail_let!(Some(disk) = manifest.disk => "No disk layout to plan");
+		let arch = cli.arch.or(manifest.dnf.arch).unwrap_or_else(|| std::env::consts::ARCH.to_string());
+		print!("{}", disk.with_verity()?.plan_table(&arch));
+		return Ok(());
+	}
+
+	Privilege::for_output(output, cli.rootless).acquire()?;
 
-	let mut manifest = Manifest::load_all(&cli.config.unwrap(), cli.output)?;
+	let mut manifest = Manifest::load_all(&cli.config.unwrap(), output, &cli.include_dirs)?;
+	manifest.validate(output)?;
 
 	// check for overrides
 
@@ -116,12 +304,93 @@ pub fn parse(cli: KatsuCli) -> Result<()> {
 		manifest.out_file = Some(output_file.into_os_string().into_string().unwrap());
 	}
 
+	if cli.continue_on_script_error {
+		let scripts = &mut manifest.scripts;
+		scripts.pre.iter_mut().chain(scripts.post.iter_mut()).for_each(|s| s.ignore_errors = true);
+	}
+
+	if let Some(path) = cli.summary_json {
+		match manifest.disk.as_mut() {
+			Some(disk) => disk.summary_json = Some(path),
+			None => tracing::warn!("--summary-json is only used for disk images, ignoring"),
+		}
+	}
+
+	manifest.dnf.koji_tags.extend(cli.koji_tags);
+	if let Some(dir) = cli.offline {
+		manifest.dnf.offline_repo = Some(dir);
+	}
+	manifest.preserve_permissions |= cli.preserve_permissions;
+	manifest.strip_rpmdb |= cli.strip_rpmdb;
+	if cli.seed_rng {
+		manifest.random_seed.get_or_insert_with(Default::default);
+	}
+	if cli.embed_manifest {
+		manifest.embed_manifest.get_or_insert_with(|| PathBuf::from(DEFAULT_EMBED_MANIFEST));
+	}
+
+	if cli.smoke_test {
+		let iso = manifest.iso.get_or_insert_with(Default::default);
+		iso.smoke_test.get_or_insert_with(Default::default);
+	}
+
+	if let Some(algorithm) = cli.rootfs_compression {
+		let level = manifest.compression.filter(|c| c.algorithm == algorithm).and_then(|c| c.level);
+		manifest.compression = Some(Compression { algorithm, level });
+	}
+
+	if cli.fail_if_larger_than_media {
+		manifest.iso.get_or_insert_with(Default::default).fail_if_larger_than_media = true;
+	}
+
+	if let Some(key) = cli.sign_iso {
+		manifest.iso.get_or_insert_with(Default::default).sign_key = Some(key);
+	}
+
 	trace!(?manifest, "Loaded manifest");
 
-	let builder = KatsuBuilder::new(manifest, cli.output, cli.skip_phases.unwrap_or_default())?;
+	manifest.apply_environment();
+
+	let mut stamp = None;
+	if cli.since_manifest_hash {
+		match KatsuBuilder::artifacts(output, &manifest).into_iter().next() {
+			Some(artifact) => {
+				let hash = manifest.content_hash(output)?;
+				let build_stamp = crate::util::BuildStamp::new(&artifact, hash);
+				if build_stamp.is_current(&artifact) {
+					tracing::info!(?artifact, "Manifest unchanged since the last build, skipping");
+					return Ok(());
+				}
+				stamp = Some(build_stamp);
+			},
+			None => tracing::warn!("--since-manifest-hash needs a file output, ignoring"),
+		}
+	}
+
+	let builder = KatsuBuilder::new(manifest, output, cli.skip_phases.unwrap_or_default())?;
 
 	tracing::info!("Building image");
 	builder.build()?;
+	if let Some(stamp) = &stamp {
+		stamp.write()?;
+	}
+
+	let owner = crate::util::output_owner(cli.output_owner.as_deref(), |v| std::env::var(v).ok())?;
+	if let Some((uid, gid)) = owner {
+		let stamp = stamp.as_ref().map(|s| s.path().to_path_buf());
+		for artifact in KatsuBuilder::artifacts(output, &builder.manifest).into_iter().chain(stamp) {
+			tracing::debug!(?artifact, uid, gid, "Changing owner of output");
+			cmd_lib::run_cmd!(chown -R $uid:$gid $artifact)?;
+		}
+	}
 
 	Ok(())
 }
+
+#[test]
+fn test_rootless_privilege() {
+	assert_eq!(Privilege::for_output(OutputFormat::Folder, true), Privilege::Rootless);
+	assert_eq!(Privilege::for_output(OutputFormat::Folder, false), Privilege::Sudo);
+	assert_eq!(Privilege::for_output(OutputFormat::DiskImage, true), Privilege::Sudo);
+	assert_eq!(Privilege::for_output(OutputFormat::Device, true), Privilege::Sudo);
+}
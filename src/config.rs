@@ -11,20 +11,511 @@ use std::{
 };
 use tracing::{debug, info, trace, warn};
 const DEFAULT_VOLID: &str = "KATSU-LIVEOS";
+pub const NO_DISK_LAYOUT: &str =
+	"Disk layout not specified, add a `disk` section with `size` and `partitions` to the manifest";
+pub const SYSLINUX_NO_DISK: &str =
+	"Syslinux only boots ISOs, use grub or grub-bios for disk images";
+const DEFAULT_HOSTS: &str =
+	"127.0.0.1   localhost localhost.localdomain localhost4 localhost4.localdomain4
+::1         localhost localhost.localdomain localhost6 localhost6.localdomain6
+";
 
-#[derive(Deserialize, Debug, Clone, Serialize)]
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
 pub struct IsoConfig {
 	/// Volume ID for the ISO image
 	#[serde(default)]
 	pub volume_id: Option<String>,
+	/// What to do with a volume ID that ISO 9660 doesn't allow: `warn` (the default),
+	/// `normalize` it to uppercase with other characters replaced by `_`, or `strict` to fail.
+	/// IDs longer than 32 characters always fail
+	#[serde(default)]
+	pub volume_id_check: VolumeIdCheck,
+	/// GPG key ID to sign the ISO with, producing a detached `<image>.asc` signature
+	#[serde(default)]
+	pub sign_key: Option<String>,
+	/// Options for the EFI system partition image (`boot/efiboot.img`)
+	#[serde(default)]
+	pub efiboot: EfibootConfig,
+	/// Skip the hybrid MBR on x86_64, for media that only needs to boot from GPT/UEFI
+	#[serde(default)]
+	pub gpt_only: bool,
+	/// Directory on the ISO holding the root filesystem image, defaults to `LiveOS`
+	#[serde(default)]
+	pub live_dir: Option<String>,
+	/// File name of the root filesystem image, defaults to `squashfs.img`
+	#[serde(default)]
+	pub rootfs_name: Option<String>,
+	/// Names of the shim and GRUB EFI binaries per architecture, for distros that don't use
+	/// Fedora's names
+	#[serde(default)]
+	pub efi_bins: BTreeMap<String, EfiBinNames>,
+	/// Extra files to put at the root of the ISO (outside the root filesystem image), such as
+	/// `README.txt` or `LICENSE`
+	#[serde(default)]
+	pub root_files: Vec<IsoRootFile>,
+	/// Extra initrd images in the chroot's `/boot` loaded before the initramfs, such as
+	/// early microcode
+	#[serde(default)]
+	pub early_initrds: Vec<String>,
+	/// Extra initrd images in the chroot's `/boot` loaded after the initramfs, such as overlays
+	#[serde(default)]
+	pub extra_initrds: Vec<String>,
+	/// Build GRUB's EFI binary with `grub2-mkimage` from the chroot's GRUB modules (e.g.
+	/// `grub2-efi-x64-modules`) instead of using the distro's prebuilt one. This is done anyway
+	/// when the chroot has no prebuilt GRUB
+	#[serde(default)]
+	pub grub_mkimage: bool,
+	/// Also list the Boot Loader Specification entries from the image's `/boot/loader/entries`
+	/// in the GRUB menu (with `blscfg`), rewritten to boot the live media
+	#[serde(default)]
+	pub bls: bool,
+	/// Path of a `grub.cfg` in the root filesystem image (e.g. `/boot/grub2/grub.cfg`) that the
+	/// ISO's GRUB loads with `configfile`, falling back to Katsu's menu if it returns
+	#[serde(default)]
+	pub grub_configfile: Option<PathBuf>,
+	/// Seconds the GRUB menu waits before booting the default entry, defaults to 60
+	#[serde(default)]
+	pub grub_timeout: Option<u32>,
+	/// How GRUB shows the menu while waiting. With `hidden`, holding Shift shows it
+	#[serde(default)]
+	pub grub_timeout_style: Option<GrubTimeoutStyle>,
+	/// Media the ISO is meant to be written to: `cd` (700 MiB), `dvd` (4.7 GB) or a size. A
+	/// larger ISO is reported with a warning, or fails the build with `fail_if_larger_than_media`
+	#[serde(default)]
+	pub media_size: Option<MediaSize>,
+	#[serde(default)]
+	pub fail_if_larger_than_media: bool,
+	/// Boot the ISO in QEMU after building it, failing the build if it doesn't come up
+	#[serde(default)]
+	pub smoke_test: Option<SmokeTestConfig>,
+}
+
+const MAX_VOLID_LEN: usize = 32;
+
+/// Handling of volume IDs with characters ISO 9660 doesn't allow
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeIdCheck {
+	#[default]
+	Warn,
+	Normalize,
+	Strict,
+}
+
+/// Uppercases `volid` and replaces what isn't an ISO 9660 d-character with `_`
+///
+/// `-` isn't a d-character either, but it is in every Fedora label and xorriso keeps it.
+fn normalize_volid(volid: &str) -> String {
+	volid
+		.chars()
+		.map(|c| c.to_ascii_uppercase())
+		.map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+		.collect()
+}
+
+/// Capacity of the media an ISO is written to
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum MediaSize {
+	Media(Media),
+	Size(ByteSize),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Media {
+	Cd,
+	Dvd,
+}
+
+impl MediaSize {
+	pub fn size(&self) -> ByteSize {
+		match self {
+			Self::Media(Media::Cd) => ByteSize::mib(700),
+			Self::Media(Media::Dvd) => ByteSize::gb(4) + ByteSize::mb(700),
+			Self::Size(size) => *size,
+		}
+	}
+
+	/// Checks that an ISO of `size` bytes fits on the media, failing if it doesn't and `fail`
+	/// is set, warning otherwise
+	pub fn check(&self, size: u64, fail: bool) -> Result<()> {
+		let capacity = self.size();
+		if size <= capacity.as_u64() {
+			debug!(size, %capacity, "ISO fits on the media");
+			return Ok(());
+		}
+		let over = ByteSize::b(size - capacity.as_u64());
+		if fail {
+			color_eyre::eyre::bail!(
+				"ISO is {} larger than the media ({}, {capacity})",
+				over.to_string_as(true),
+				self.name()
+			);
+		}
+		warn!(%over, %capacity, "ISO is larger than the media");
+		Ok(())
+	}
+
+	fn name(&self) -> String {
+		match self {
+			Self::Media(Media::Cd) => "cd".to_string(),
+			Self::Media(Media::Dvd) => "dvd".to_string(),
+			Self::Size(size) => size.to_string(),
+		}
+	}
+}
+
+/// GRUB's `timeout_style`
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GrubTimeoutStyle {
+	Menu,
+	Countdown,
+	Hidden,
+}
+
+/// Options for the QEMU smoke test of the built ISO
+///
+/// The serial console is watched for `marker`, so the kernel command line needs
+/// `console=ttyS0` (or `console=ttyAMA0` on aarch64).
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct SmokeTestConfig {
+	/// Seconds to wait for the marker, defaults to 300
+	#[serde(default)]
+	pub timeout: Option<u64>,
+	/// Text on the serial console that means the image booted, defaults to `login:`
+	#[serde(default)]
+	pub marker: Option<String>,
+	/// Memory for the VM in MiB, defaults to 2048
+	#[serde(default)]
+	pub memory: Option<u32>,
+	/// UEFI firmware to boot with, defaults to the edk2 firmware for the architecture
+	#[serde(default)]
+	pub firmware: Option<PathBuf>,
+}
+
+impl SmokeTestConfig {
+	pub fn get_timeout(&self) -> std::time::Duration {
+		std::time::Duration::from_secs(self.timeout.unwrap_or(300))
+	}
+
+	pub fn get_marker(&self) -> &str {
+		self.marker.as_deref().unwrap_or("login:")
+	}
+
+	pub fn get_firmware(&self, arch: &str) -> Result<PathBuf> {
+		if let Some(firmware) = &self.firmware {
+			return Ok(firmware.clone());
+		}
+		Ok(PathBuf::from(match arch {
+			"x86_64" => "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+			"aarch64" => "/usr/share/edk2/aarch64/QEMU_EFI.fd",
+			_ => color_eyre::eyre::bail!(
+				"No UEFI firmware known for {arch}, set iso.smoke_test.firmware"
+			),
+		}))
+	}
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct IsoRootFile {
+	/// File to copy, relative to the manifest
+	pub source: PathBuf,
+	/// Path relative to the root of the ISO, defaults to the file name of `source`
+	#[serde(default)]
+	pub dest: Option<PathBuf>,
+}
+
+impl IsoRootFile {
+	/// Where the file goes in the ISO tree `tree`
+	pub fn dest_in(&self, tree: &Path) -> Result<PathBuf> {
+		let dest = match &self.dest {
+			Some(dest) => dest.as_path(),
+			None => Path::new(self.source.file_name().unwrap_or_default()),
+		};
+		let dest = dest.strip_prefix("/").unwrap_or(dest);
+		if dest.as_os_str().is_empty()
+			|| dest.components().any(|c| !matches!(c, std::path::Component::Normal(_)))
+		{
+			color_eyre::eyre::bail!("Invalid ISO root file destination: {}", dest.display());
+		}
+		Ok(tree.join(dest))
+	}
+}
+
+/// When a file in `copy_files` is copied into the image
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CopyStage {
+	/// After the pre-install scripts, before packages are installed
+	#[default]
+	Pre,
+	/// After packages are installed, before the post-install scripts
+	AfterInstall,
+	/// After the post-install scripts
+	Post,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct CopyFile {
+	/// File to copy, relative to the manifest
+	pub source: PathBuf,
+	/// Path in the image
+	pub dest: PathBuf,
+	#[serde(default)]
+	pub stage: CopyStage,
+}
+
+impl CopyFile {
+	/// Where the file goes in `chroot`
+	pub fn dest_in(&self, chroot: &Path) -> Result<PathBuf> {
+		let dest = self.dest.strip_prefix("/").unwrap_or(&self.dest);
+		if dest.as_os_str().is_empty()
+			|| dest.components().any(|c| !matches!(c, std::path::Component::Normal(_)))
+		{
+			color_eyre::eyre::bail!("Invalid copy_files destination: {}", self.dest.display());
+		}
+		Ok(chroot.join(dest))
+	}
+}
+
+/// Names of the EFI binaries in the chroot's EFI directory
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct EfiBinNames {
+	/// Defaults to `shim<arch>.efi`, e.g. `shimx64.efi`
+	#[serde(default)]
+	pub shim: Option<String>,
+	/// Defaults to `grub<arch>.efi`, e.g. `grubx64.efi`
+	#[serde(default)]
+	pub grub: Option<String>,
+}
+
+/// Architecture suffix used in EFI binary names
+pub fn efi_arch(arch: &str) -> Result<&'static str> {
+	Ok(match arch {
+		"x86_64" => "x64",
+		"aarch64" => "aa64",
+		_ => color_eyre::eyre::bail!("No EFI binary names known for {arch}"),
+	})
+}
+
+const DEFAULT_LIVE_DIR: &str = "LiveOS";
+const DEFAULT_ROOTFS_NAME: &str = "squashfs.img";
+
+const DEFAULT_EFIBOOT_LABEL: &str = "EFI";
+const DEFAULT_EFIBOOT_SIZE: ByteSize = ByteSize::mib(25);
+
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct EfibootConfig {
+	/// Filesystem label, defaults to `EFI`
+	#[serde(default)]
+	pub label: Option<String>,
+	/// FAT type (12, 16 or 32), picked by `mkfs.fat` from the size if unset
+	#[serde(default)]
+	pub fat: Option<u8>,
+	/// Size of the image, defaults to 25 MiB. FAT32 needs at least ~33 MiB
+	#[serde(default)]
+	pub size: Option<ByteSize>,
+}
+
+impl EfibootConfig {
+	pub fn get_label(&self) -> &str {
+		self.label.as_deref().unwrap_or(DEFAULT_EFIBOOT_LABEL)
+	}
+
+	pub fn get_size(&self) -> ByteSize {
+		self.size.unwrap_or(DEFAULT_EFIBOOT_SIZE)
+	}
+}
+
+/// Compression algorithms for the root image or the output image
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compressor {
+	/// Uncompressed, for the fastest boots from fast storage
+	#[serde(alias = "store")]
+	#[value(alias = "store")]
+	None,
+	Gzip,
+	Lzo,
+	Lz4,
+	Xz,
+	Zstd,
+	Lzma,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub struct Compression {
+	pub algorithm: Compressor,
+	/// Compression level, defaults to [`Compression::default_level`]
+	#[serde(default)]
+	pub level: Option<u32>,
+}
+
+impl Compression {
+	/// The compression used for `output` when the manifest doesn't set `compression`
+	///
+	/// | Output                | Compressed                              | Default |
+	/// |-----------------------|-----------------------------------------|---------|
+	/// | `iso`, `pxe`          | squashfs root image                     | zstd 19 |
+	/// | `disk-image`          | nothing, opt in with `disk.compression` | none    |
+	/// | `folder`, `device`    | nothing                                 | none    |
+	pub fn default_for(output: OutputFormat) -> Self {
+		let algorithm = match output {
+			OutputFormat::Iso | OutputFormat::Pxe => Compressor::Zstd,
+			OutputFormat::DiskImage | OutputFormat::Folder | OutputFormat::Device => {
+				Compressor::None
+			},
+		};
+		Self { algorithm, level: None }.resolve(output)
+	}
+
+	/// Level used for `algorithm` on `output` when none is set, `None` if it takes no level
+	pub fn default_level(algorithm: Compressor, output: OutputFormat) -> Option<u32> {
+		let level = match algorithm {
+			Compressor::Gzip | Compressor::Xz => 9,
+			Compressor::Lzo => 8,
+			Compressor::Zstd => 19,
+			Compressor::None | Compressor::Lz4 | Compressor::Lzma => return None,
+		};
+		Self { algorithm, level: None }.level_range(output).map(|_| level)
+	}
+
+	/// Fills in [`Compression::default_level`] if no level is set
+	pub fn resolve(self, output: OutputFormat) -> Self {
+		Self { level: self.level.or(Self::default_level(self.algorithm, output)), ..self }
+	}
+
+	/// Levels the tool compressing `output` accepts for this algorithm, `None` if it takes no
+	/// level
+	pub fn level_range(&self, output: OutputFormat) -> Option<std::ops::RangeInclusive<u32>> {
+		match (output, self.algorithm) {
+			// mksquashfs
+			(OutputFormat::Iso | OutputFormat::Pxe, Compressor::Gzip | Compressor::Lzo) => {
+				Some(1..=9)
+			},
+			(OutputFormat::Iso | OutputFormat::Pxe, Compressor::Zstd) => Some(1..=22),
+			// xz, zstd and gzip on the disk image, zstd needs --ultra past 19
+			(OutputFormat::DiskImage, Compressor::Xz) => Some(0..=9),
+			(OutputFormat::DiskImage, Compressor::Zstd) => Some(1..=19),
+			(OutputFormat::DiskImage, Compressor::Gzip) => Some(1..=9),
+			_ => None,
+		}
+	}
+
+	/// Checks that `level` can be used with the algorithm for `output`
+	pub fn check_level(&self, output: OutputFormat) -> Result<()> {
+		let Some(level) = self.level else { return Ok(()) };
+		if matches!(output, OutputFormat::Folder | OutputFormat::Device) {
+			return Ok(());
+		}
+		let algorithm = format!("{:?}", self.algorithm).to_lowercase();
+		match self.level_range(output) {
+			None => color_eyre::eyre::bail!("{algorithm} compression doesn't take a level"),
+			Some(range) if !range.contains(&level) => color_eyre::eyre::bail!(
+				"{algorithm} compression level {level} is not between {} and {}",
+				range.start(),
+				range.end()
+			),
+			Some(_) => Ok(()),
+		}
+	}
+
+	/// `mksquashfs` options for this compression, see [`Compression::resolve`] for the level
+	pub fn mksquashfs_args(&self) -> Vec<String> {
+		let level = || {
+			self.level
+				.map_or(vec![], |level| vec!["-Xcompression-level".to_string(), level.to_string()])
+		};
+		let (comp, extra) = match self.algorithm {
+			Compressor::None => {
+				return ["-noI", "-noD", "-noF", "-noX"].map(String::from).to_vec();
+			},
+			Compressor::Gzip => ("gzip", level()),
+			Compressor::Lzo => ("lzo", level()),
+			Compressor::Lz4 => ("lz4", vec!["-Xhc".to_string()]),
+			Compressor::Xz => ("xz", vec!["-Xbcj".to_string(), "x86".to_string()]),
+			Compressor::Zstd => ("zstd", level()),
+			Compressor::Lzma => ("lzma", vec![]),
+		};
+		[vec!["-comp".to_string(), comp.to_string()], extra].concat()
+	}
+
+	/// `mkfs.erofs` options for this compression
+	pub fn mkfs_erofs_args(&self) -> Result<Vec<String>> {
+		let comp = match self.algorithm {
+			Compressor::None => return Ok(vec![]),
+			Compressor::Gzip => "deflate",
+			Compressor::Lz4 => "lz4hc",
+			Compressor::Xz | Compressor::Lzma => "lzma",
+			Compressor::Zstd => "zstd",
+			Compressor::Lzo => color_eyre::eyre::bail!("EROFS does not support lzo compression"),
+		};
+		Ok(vec![match self.level {
+			Some(level) => format!("-z{comp},{level}"),
+			None => format!("-z{comp}"),
+		}])
+	}
 }
 
 impl IsoConfig {
+	/// All initrd images of a boot entry in load order, around the main `initramfs`
+	pub fn initrds(&self, initramfs: &str) -> Vec<String> {
+		let mut initrds = self.early_initrds.clone();
+		initrds.push(initramfs.to_string());
+		initrds.extend(self.extra_initrds.iter().cloned());
+		initrds
+	}
+
 	pub fn get_volid(&self) -> String {
-		if let Some(volid) = &self.volume_id {
-			volid.clone()
-		} else {
-			DEFAULT_VOLID.to_string()
+		let volid = self.volume_id.as_deref().unwrap_or(DEFAULT_VOLID);
+		match self.volume_id_check {
+			VolumeIdCheck::Normalize => normalize_volid(volid),
+			VolumeIdCheck::Warn | VolumeIdCheck::Strict => volid.to_string(),
+		}
+	}
+
+	/// Checks the volume ID against ISO 9660, see [`IsoConfig::volume_id_check`]
+	pub fn check_volid(&self) -> Result<()> {
+		let volid = self.volume_id.as_deref().unwrap_or(DEFAULT_VOLID);
+		if volid.chars().count() > MAX_VOLID_LEN {
+			color_eyre::eyre::bail!(
+				"Volume ID `{volid}` is longer than the {MAX_VOLID_LEN} characters ISO 9660 allows"
+			);
+		}
+		let normalized = normalize_volid(volid);
+		if normalized == volid {
+			return Ok(());
+		}
+		match self.volume_id_check {
+			VolumeIdCheck::Warn => {
+				warn!(
+					volid,
+					"Volume ID has characters ISO 9660 doesn't allow, the media may differ"
+				);
+			},
+			VolumeIdCheck::Normalize => warn!(volid, normalized, "Normalized volume ID"),
+			VolumeIdCheck::Strict => color_eyre::eyre::bail!(
+				"Volume ID `{volid}` can only have uppercase letters, digits, `_` and `-`"
+			),
+		}
+		Ok(())
+	}
+
+	/// File name of the shim EFI binary for `arch`
+	pub fn shim_name(&self, arch: &str) -> Result<String> {
+		match self.efi_bins.get(arch).and_then(|b| b.shim.clone()) {
+			Some(shim) => Ok(shim),
+			None => Ok(format!("shim{}.efi", efi_arch(arch)?)),
+		}
+	}
+
+	/// File name of the GRUB EFI binary for `arch`
+	pub fn grub_name(&self, arch: &str) -> Result<String> {
+		match self.efi_bins.get(arch).and_then(|b| b.grub.clone()) {
+			Some(grub) => Ok(grub),
+			None => Ok(format!("grub{}.efi", efi_arch(arch)?)),
 		}
 	}
 }
@@ -56,6 +547,10 @@ pub struct Manifest {
 	#[serde(default)]
 	pub scripts: ScriptsManifest,
 
+	/// Files to copy into the image, by default after the pre-install scripts
+	#[serde(default)]
+	pub copy_files: Vec<CopyFile>,
+
 	/// Users to add to the image
 	#[serde(default)]
 	pub users: Vec<Auth>,
@@ -63,6 +558,83 @@ pub struct Manifest {
 	/// Extra parameters to the kernel command line in bootloader configs
 	pub kernel_cmdline: Option<String>,
 
+	/// Replaces `kernel_cmdline` for entries booted from BIOS
+	#[serde(default)]
+	pub kernel_cmdline_bios: Option<String>,
+
+	/// Replaces `kernel_cmdline` for entries booted from UEFI
+	#[serde(default)]
+	pub kernel_cmdline_uefi: Option<String>,
+
+	/// Console keymap, written to `/etc/vconsole.conf`
+	#[serde(default)]
+	pub keymap: Option<String>,
+
+	/// X11 keyboard layout, only written if X11 is installed.
+	/// Defaults to `keymap` without its variant (`de-latin1` becomes `de`)
+	#[serde(default)]
+	pub x11_layout: Option<String>,
+
+	/// For folder output, write the ownership and permissions of the tree to `<out>.pf`, a
+	/// `mksquashfs -pf` pseudo file, so they can be restored after a rootless build
+	#[serde(default)]
+	pub preserve_permissions: bool,
+
+	/// Swap on zram, configured with `zram-generator`
+	#[serde(default)]
+	pub zram: Option<ZramConfig>,
+
+	/// Options for generating the initramfs
+	#[serde(default)]
+	pub dracut: DracutConfig,
+	/// Stripping and signing of the kernel modules, done before the initramfs is generated
+	#[serde(default)]
+	pub kernel_modules: KernelModulesConfig,
+
+	/// Preseed `/var/lib/systemd/random-seed` from the host's CSPRNG. Every build gets a
+	/// different seed, so the image is no longer reproducible.
+	#[serde(default)]
+	pub random_seed: Option<RandomSeedConfig>,
+
+	/// Static entries appended to `/etc/hosts`, as IP address to host names
+	#[serde(default)]
+	pub hosts: BTreeMap<String, Vec<String>>,
+
+	/// Environment variables set for every command run during the build, such as proxy
+	/// settings or `DNF5_FORCE_INTERACTIVE`. They override variables of the same name from the
+	/// environment Katsu was started in
+	#[serde(default)]
+	pub environment: BTreeMap<String, String>,
+
+	/// Directories mounted as an empty tmpfs at runtime, through `/etc/fstab`, so they're
+	/// writable (and discarded on reboot) on a read-only or live root, e.g. `/var/log`
+	#[serde(default)]
+	pub tmpfs: Vec<TmpfsMount>,
+
+	/// Remove the RPM database at the end of the build, after writing the list of installed
+	/// packages to `/usr/lib/katsu/packages.txt`. For images that never install packages at
+	/// runtime, such as OCI-derived ones.
+	#[serde(default)]
+	pub strip_rpmdb: bool,
+	/// Run `systemd-sysusers` against the image after installing packages, creating the system
+	/// users and groups declared by packages at build time instead of on first boot
+	#[serde(default)]
+	pub sysusers: bool,
+	/// Run `systemd-tmpfiles --create` against the image after installing packages, creating
+	/// the files and directories declared by packages at build time
+	#[serde(default)]
+	pub tmpfiles: bool,
+
+	/// Write the resolved manifest (after imports and overrides) as JSON to this path in the
+	/// image, usually `/usr/lib/katsu/manifest.json`. Everything is included, such as the
+	/// users' password hashes
+	#[serde(default)]
+	pub embed_manifest: Option<PathBuf>,
+	/// Compression of the root filesystem image of ISO and PXE outputs, see
+	/// [`Compression::default_for`] for the defaults. Disk images use `disk.compression`
+	#[serde(default)]
+	pub compression: Option<Compression>,
+
 	/// ISO config (optional)
 	/// This is only used for ISO images
 	#[serde(default)]
@@ -83,7 +655,23 @@ where
 	Ok(Bootloader::from(s.as_str()))
 }
 
+/// Firmware the boot entries are generated for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+	Bios,
+	Uefi,
+}
+
 impl Manifest {
+	/// Extra kernel command line for the given boot mode
+	pub fn get_cmdline(&self, mode: BootMode) -> &str {
+		let cmdline = match mode {
+			BootMode::Bios => &self.kernel_cmdline_bios,
+			BootMode::Uefi => &self.kernel_cmdline_uefi,
+		};
+		cmdline.as_ref().or(self.kernel_cmdline.as_ref()).map_or("", |s| s)
+	}
+
 	pub fn get_volid(&self) -> String {
 		if let Some(iso) = &self.iso {
 			iso.get_volid()
@@ -91,28 +679,178 @@ impl Manifest {
 			DEFAULT_VOLID.to_string()
 		}
 	}
+
+	/// Path of the root filesystem image, relative to the root of the ISO
+	pub fn get_rootfs_path(&self) -> PathBuf {
+		let iso = self.iso.as_ref();
+		let live_dir = iso.and_then(|iso| iso.live_dir.as_deref()).unwrap_or(DEFAULT_LIVE_DIR);
+		let rootfs = iso.and_then(|iso| iso.rootfs_name.as_deref()).unwrap_or(DEFAULT_ROOTFS_NAME);
+		PathBuf::from(live_dir).join(rootfs)
+	}
+
+	/// dmsquash-live arguments for finding the root filesystem image if it's not in the default
+	/// location
+	pub fn get_live_args(&self) -> String {
+		let iso = self.iso.as_ref();
+		let mut args = vec![];
+		if let Some(live_dir) = iso.and_then(|iso| iso.live_dir.as_ref()) {
+			args.push(format!("rd.live.dir={live_dir}"));
+		}
+		if let Some(rootfs) = iso.and_then(|iso| iso.rootfs_name.as_ref()) {
+			args.push(format!("rd.live.squashimg={rootfs}"));
+		}
+		args.join(" ")
+	}
+
+	/// Contents of `/etc/vconsole.conf` for [`Manifest::keymap`]
+	pub fn vconsole_conf(&self) -> Option<String> {
+		self.keymap.as_ref().map(|keymap| format!("KEYMAP={keymap}\n"))
+	}
+
+	/// Contents of the `xorg.conf.d` keyboard snippet for [`Manifest::x11_layout`]
+	pub fn xorg_keyboard_conf(&self) -> Option<String> {
+		let layout = self.x11_layout.as_deref().or_else(|| {
+			self.keymap.as_deref().map(|keymap| keymap.split_once('-').map_or(keymap, |(l, _)| l))
+		})?;
+		Some(format!(
+			"Section \"InputClass\"\n\tIdentifier \"system-keyboard\"\n\tMatchIsKeyboard \"on\"\n\tOption \"XkbLayout\" \"{layout}\"\nEndSection\n"
+		))
+	}
+
+	/// Writes the keyboard configuration to the chroot
+	pub fn write_keymap(&self, chroot: &Path) -> Result<()> {
+		if let Some(conf) = self.vconsole_conf() {
+			info!(keymap = self.keymap, "Setting console keymap");
+			crate::util::just_write(chroot.join("etc/vconsole.conf"), conf)?;
+		}
+		// only bother with X11 if it's installed
+		if !chroot.join("etc/X11").is_dir() {
+			return Ok(());
+		}
+		if let Some(conf) = self.xorg_keyboard_conf() {
+			info!("Setting X11 keyboard layout");
+			crate::util::just_write(chroot.join("etc/X11/xorg.conf.d/00-keyboard.conf"), conf)?;
+		}
+		Ok(())
+	}
+
+	pub fn to_json(&self) -> Result<String> {
+		Ok(serde_json::to_string_pretty(self)?)
+	}
+
+	/// Hash of the manifest for `output` and the files it references (scripts, `copy_files` and
+	/// ISO root files), which changes whenever the build would change
+	pub fn content_hash(&self, output: OutputFormat) -> Result<String> {
+		use sha2::{Digest, Sha256};
+		let mut hasher = Sha256::new();
+		hasher.update(serde_json::to_string(&output)?);
+		hasher.update(self.to_json()?);
+		let scripts =
+			self.scripts.pre.iter().chain(&self.scripts.post).filter_map(|s| s.file.as_ref());
+		let copied = self.copy_files.iter().map(|f| &f.source);
+		let root_files = self.iso.iter().flat_map(|iso| &iso.root_files).map(|f| &f.source);
+		for path in scripts.chain(copied).chain(root_files) {
+			hasher.update(path.as_os_str().as_encoded_bytes());
+			hasher.update(b"\0");
+			// missing files fail later in the build with a better error
+			if let Ok(content) = std::fs::read(path) {
+				hasher.update(content);
+			}
+			hasher.update(b"\0");
+		}
+		Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+	}
+
+	/// Writes the manifest into `chroot` if `embed_manifest` is set
+	pub fn write_embedded(&self, chroot: &Path) -> Result<()> {
+		let Some(dest) = &self.embed_manifest else { return Ok(()) };
+		let dest = dest.strip_prefix("/").unwrap_or(dest);
+		if dest.as_os_str().is_empty()
+			|| dest.components().any(|c| !matches!(c, std::path::Component::Normal(_)))
+		{
+			color_eyre::eyre::bail!("Invalid embed_manifest path: {}", dest.display());
+		}
+		info!(?dest, "Embedding manifest");
+		crate::util::just_write(chroot.join(dest), self.to_json()?)
+	}
+
+	/// `/etc/hosts` with the `hosts` entries appended to `existing`
+	pub fn hosts_file(&self, existing: &str) -> String {
+		let mut hosts = existing.to_string();
+		if !hosts.is_empty() && !hosts.ends_with('\n') {
+			hosts.push('\n');
+		}
+		for (ip, names) in &self.hosts {
+			hosts += &format!("{ip} {}\n", names.join(" "));
+		}
+		hosts
+	}
+
+	pub fn write_hosts(&self, chroot: &Path) -> Result<()> {
+		if self.hosts.is_empty() {
+			return Ok(());
+		}
+		info!("Adding /etc/hosts entries");
+		let path = chroot.join("etc/hosts");
+		// normally installed by the `setup` package
+		let existing = std::fs::read_to_string(&path).unwrap_or_else(|_| DEFAULT_HOSTS.to_string());
+		crate::util::just_write(path, self.hosts_file(&existing))
+	}
+
+	/// Sets [`Manifest::environment`] in Katsu's own environment, replacing existing values,
+	/// so every command it runs inherits them
+	pub fn apply_environment(&self) {
+		for (key, value) in &self.environment {
+			debug!(key, value, "Setting environment variable");
+			std::env::set_var(key, value);
+		}
+	}
+
+	/// Appends the [`Manifest::tmpfs`] mounts to the chroot's `/etc/fstab`
+	pub fn write_tmpfs(&self, chroot: &Path) -> Result<()> {
+		if self.tmpfs.is_empty() {
+			return Ok(());
+		}
+		info!("Adding tmpfs mounts to /etc/fstab");
+		let path = chroot.join("etc/fstab");
+		let mut fstab = std::fs::read_to_string(&path).unwrap_or_default();
+		if !fstab.is_empty() && !fstab.ends_with('\n') {
+			fstab.push('\n');
+		}
+		self.tmpfs.iter().for_each(|mount| fstab += &mount.fstab_entry());
+		crate::util::just_write(path, fstab)
+	}
+
+	/// Copy the `copy_files` of `stage` into `chroot`
+	pub fn copy_files_at(&self, chroot: &Path, stage: CopyStage) -> Result<()> {
+		for file in self.copy_files.iter().filter(|f| f.stage == stage) {
+			let dest = file.dest_in(chroot)?;
+			debug!(source = ?file.source, ?dest, ?stage, "Copying file into chroot");
+			std::fs::create_dir_all(dest.parent().unwrap())?;
+			std::fs::copy(&file.source, &dest)?;
+		}
+		Ok(())
+	}
+
 	/// Loads a single manifest from a file
-	pub fn load(path: &Path) -> Result<Self> {
+	///
+	/// Relative paths in the manifest are resolved against the manifest's directory first,
+	/// then against each of `include_dirs` in order.
+	pub fn load(path: &Path, include_dirs: &[PathBuf]) -> Result<Self> {
 		let mut manifest: Self = serde_yaml::from_str(&std::fs::read_to_string(path)?)?;
 
 		// get dir of path relative to cwd
 
-		fn path_not_exists_error(path: &Path) -> color_eyre::eyre::Report {
-			tracing::error!(?path, "Path does not exist");
-			color_eyre::eyre::eyre!("Path does not exist: {path:#?}", path = path)
-		}
-
 		let mut path_can = path.canonicalize()?;
 
 		path_can.pop();
 		trace!(path = ?path_can, "Canonicalizing path");
 
+		let resolve = |p: &Path| resolve_path(&path_can, p, include_dirs);
+
 		for import in &mut manifest.import {
 			debug!("Import: {import:#?}");
-			if !path_can.join(&import).exists() {
-				return Err(path_not_exists_error(&path_can.join(&import)));
-			}
-			*import = path_can.join(&import).canonicalize()?;
+			*import = resolve(import)?;
 			debug!("Canonicalized import: {import:#?}");
 		}
 
@@ -121,41 +859,40 @@ impl Manifest {
 		for script in &mut manifest.scripts.pre {
 			if let Some(f) = script.file.as_mut() {
 				trace!(?f, "Loading pre scripts");
-				if !path_can.join(&f).exists() {
-					return Err(path_not_exists_error(&path_can.join(&f)));
-				}
-				*f = path_can.join(&f).canonicalize()?;
+				*f = resolve(f)?;
 			}
 		}
 
 		for script in &mut manifest.scripts.post {
 			if let Some(f) = script.file.as_mut() {
-				if !path_can.join(&f).exists() {
-					return Err(path_not_exists_error(&path_can.join(&f)));
-				}
 				trace!(?f, "Loading post scripts");
-				*f = path_can.join(&f).canonicalize()?;
+				*f = resolve(f)?;
+			}
+		}
+
+		for file in &mut manifest.copy_files {
+			file.source = resolve(&file.source)?;
+		}
+
+		if let Some(iso) = &mut manifest.iso {
+			for file in &mut iso.root_files {
+				file.source = resolve(&file.source)?;
 			}
 		}
 
 		//  canonicalize repodir if it exists, relative to the file that imported it
 		if let Some(repodir) = &mut manifest.dnf.repodir {
-			// check if path even exists
-			let repodir_can = path_can.join(&repodir);
-			if !repodir_can.exists() {
-				return Err(path_not_exists_error(&repodir_can));
-			}
-			*repodir = repodir_can.canonicalize()?;
+			*repodir = resolve(repodir)?;
 		}
 
 		Ok(manifest)
 	}
 
-	pub fn load_all(path: &Path, output: OutputFormat) -> Result<Self> {
+	pub fn load_all(path: &Path, output: OutputFormat, include_dirs: &[PathBuf]) -> Result<Self> {
 		use std::mem::take;
 
 		// get all imports, then merge them all
-		let mut manifest = Self::load(path)?;
+		let mut manifest = Self::load(path, include_dirs)?;
 		// do not override:
 		let bootloader = take(&mut manifest.bootloader);
 		let iso = take(&mut manifest.iso);
@@ -171,7 +908,10 @@ impl Manifest {
 		manifest.dnf.repodir = take(&mut dnf.repodir);
 
 		manifest = manifest.import.iter().try_fold(manifest.clone(), |acc, import| {
-			Result::<_>::Ok(merge_struct::merge(&acc, &Self::load_all(import, output)?)?)
+			Result::<_>::Ok(merge_struct::merge(
+				&acc,
+				&Self::load_all(import, output, include_dirs)?,
+			)?)
 		})?;
 
 		manifest.bootloader = bootloader;
@@ -180,6 +920,7 @@ impl Manifest {
 			OutputFormat::Device => todo!("DeviceBuilder not implemented?"),
 			OutputFormat::DiskImage => manifest.disk = disk.or(manifest.disk),
 			OutputFormat::Folder => manifest.out_file = None,
+			OutputFormat::Pxe => {},
 		}
 		(dnf.packages, dnf.arch_packages, dnf.arch_exclude, dnf.exclude, dnf.repodir) = (
 			manifest.dnf.packages,
@@ -192,7 +933,365 @@ impl Manifest {
 
 		manifest.dnf = dnf;
 
-		Ok(manifest)
+		Ok(manifest)
+	}
+
+	/// Compression of `output`, from `compression` (or `disk.compression` for disk images) or
+	/// [`Compression::default_for`]
+	///
+	/// Without a `level`, [`Compression::default_level`] is used.
+	pub fn compression(&self, output: OutputFormat) -> Compression {
+		let custom = match output {
+			OutputFormat::DiskImage => self.disk.as_ref().and_then(|disk| disk.compression),
+			_ => self.compression,
+		};
+		custom.map_or_else(|| Compression::default_for(output), |c| c.resolve(output))
+	}
+
+	/// Checks that the merged manifest can be built as `output`, without looking at the host
+	pub fn validate(&self, output: OutputFormat) -> Result<()> {
+		if matches!(output, OutputFormat::Iso) {
+			self.iso.clone().unwrap_or_default().check_volid()?;
+		}
+
+		if matches!(output, OutputFormat::DiskImage) {
+			crate::bail_let!(Some(disk) = &self.disk => NO_DISK_LAYOUT);
+			disk.disk_size()?;
+			disk.validate_sizes()?;
+			if disk.get_index("/").is_none() {
+				color_eyre::eyre::bail!("Disk layout has no partition mounted at `/`");
+			}
+			if self.bootloader == Bootloader::Syslinux {
+				color_eyre::eyre::bail!(SYSLINUX_NO_DISK);
+			}
+		}
+
+		let compression = self.compression(output);
+		if matches!(output, OutputFormat::DiskImage)
+			&& !matches!(
+				compression.algorithm,
+				Compressor::None | Compressor::Xz | Compressor::Zstd | Compressor::Gzip
+			) {
+			color_eyre::eyre::bail!("Disk images can only be compressed with xz, zstd or gzip");
+		}
+		compression.check_level(output)?;
+
+		for (stage, scripts) in [("pre", &self.scripts.pre), ("post", &self.scripts.post)] {
+			for script in scripts {
+				let id = script.id.as_deref().or(script.name.as_deref()).unwrap_or("<?>");
+				if script.inline.is_none() && script.file.is_none() {
+					color_eyre::eyre::bail!(
+						"Script `{id}` in scripts.{stage} has neither `file` nor `inline`"
+					);
+				}
+				for need in &script.needs {
+					if !scripts.iter().any(|s| s.id.as_ref() == Some(need)) {
+						color_eyre::eyre::bail!(
+							"Script `{need}` required by `{id}` not found in scripts.{stage}"
+						);
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Resolves a path referenced by a manifest in `base`, falling back to the include directories
+fn resolve_path(base: &Path, path: &Path, include_dirs: &[PathBuf]) -> Result<PathBuf> {
+	let Some(found) = std::iter::once(base)
+		.chain(include_dirs.iter().map(PathBuf::as_path))
+		.map(|dir| dir.join(path))
+		.find(|p| p.exists())
+	else {
+		let path = base.join(path);
+		tracing::error!(?path, ?include_dirs, "Path does not exist");
+		return Err(color_eyre::eyre::eyre!(
+			"Path does not exist: {path:#?} (also searched include dirs: {include_dirs:?})"
+		));
+	};
+	Ok(found.canonicalize()?)
+}
+
+/// Options for `/etc/systemd/zram-generator.conf`
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ZramConfig {
+	/// Size of the zram device in MiB, as an expression of `ram` (total memory in MiB)
+	#[serde(default = "zram_default_size")]
+	pub size: String,
+	/// Compression algorithm, the kernel default is used if not set
+	#[serde(default)]
+	pub compression_algorithm: Option<String>,
+}
+
+fn zram_default_size() -> String {
+	// zram-generator's own default
+	"min(ram / 2, 4096)".to_string()
+}
+
+impl ZramConfig {
+	pub const PACKAGE: &'static str = "zram-generator";
+
+	/// Generates the zram-generator config
+	pub fn config(&self) -> Result<String> {
+		crate::prepend_comment!(PREPEND: "/etc/systemd/zram-generator.conf", "swap on zram configuration", katsu::config::ZramConfig::config);
+		let (size, compression_algorithm) = (&self.size, &self.compression_algorithm);
+		Ok(crate::tpl!("zram-generator.conf.tera" => { PREPEND, size, compression_algorithm }))
+	}
+}
+
+/// Options for the preseeded `/var/lib/systemd/random-seed`
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct RandomSeedConfig {
+	/// Size of the seed in bytes, defaults to 512 like systemd-random-seed
+	#[serde(default = "random_seed_default_size")]
+	pub size: usize,
+}
+
+fn random_seed_default_size() -> usize {
+	512
+}
+
+impl Default for RandomSeedConfig {
+	fn default() -> Self {
+		Self { size: random_seed_default_size() }
+	}
+}
+
+impl RandomSeedConfig {
+	pub const PATH: &'static str = "var/lib/systemd/random-seed";
+
+	/// Writes a new seed from `/dev/urandom` to the chroot, readable only by root
+	pub fn write(&self, chroot: &Path) -> Result<()> {
+		use std::io::{Read, Write};
+		use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+		if std::env::var_os("SOURCE_DATE_EPOCH").is_some() {
+			warn!("SOURCE_DATE_EPOCH is set, but a random seed makes the image unreproducible");
+		}
+		info!(size = self.size, "Preseeding random seed");
+		let mut seed = vec![0; self.size];
+		std::fs::File::open("/dev/urandom")?.read_exact(&mut seed)?;
+
+		let path = chroot.join(Self::PATH);
+		std::fs::create_dir_all(path.parent().unwrap())?;
+		let mut file = std::fs::OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(true)
+			.mode(0o600)
+			.open(&path)?;
+		// the mode is only applied to new files
+		file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+		file.write_all(&seed)?;
+		Ok(())
+	}
+}
+
+/// A directory mounted as tmpfs at runtime
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct TmpfsMount {
+	pub path: PathBuf,
+	/// Size limit, in bytes with a `k`, `m` or `g` suffix or as a percentage of RAM like `10%`.
+	/// Defaults to tmpfs' own limit (50% of RAM)
+	#[serde(default)]
+	pub size: Option<String>,
+	/// Permissions of the mount's root, like `0755`
+	#[serde(default)]
+	pub mode: Option<String>,
+}
+
+impl TmpfsMount {
+	pub fn fstab_entry(&self) -> String {
+		let mut options = vec!["defaults".to_string()];
+		options.extend(self.size.as_ref().map(|size| format!("size={size}")));
+		options.extend(self.mode.as_ref().map(|mode| format!("mode={mode}")));
+		format!("tmpfs\t{}\ttmpfs\t{}\t0\t0\n", self.path.display(), options.join(","))
+	}
+}
+
+/// Options for `dracut`
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct DracutConfig {
+	/// Build a host-specific (`-H`) initramfs instead of a generic (`-N`) one.
+	/// Only used for disk images, live media always gets a generic initramfs
+	#[serde(default)]
+	pub hostonly: bool,
+	/// Contents of a drop-in written to `/etc/dracut.conf.d/` before packages are installed,
+	/// so every initramfs in the image is generated with it
+	#[serde(default)]
+	pub conf: Option<String>,
+	/// Remove the drop-in once the image's initramfs is generated, so it doesn't apply to
+	/// kernels installed later
+	#[serde(default)]
+	pub cleanup_conf: bool,
+}
+
+impl DracutConfig {
+	/// Path of the drop-in, relative to the chroot
+	pub const CONF_PATH: &'static str = "etc/dracut.conf.d/90-katsu.conf";
+
+	pub fn write_conf(&self, chroot: &Path) -> Result<()> {
+		crate::prepend_comment!(PREPEND: "/etc/dracut.conf.d/90-katsu.conf", "dracut configuration", katsu::config::DracutConfig::write_conf);
+		if let Some(conf) = &self.conf {
+			info!("Writing dracut drop-in");
+			crate::util::just_write(chroot.join(Self::CONF_PATH), format!("{PREPEND}{conf}"))?;
+		}
+		Ok(())
+	}
+
+	/// Removes the drop-in if `cleanup_conf` is set
+	pub fn cleanup(&self, chroot: &Path) -> Result<()> {
+		let path = chroot.join(Self::CONF_PATH);
+		if self.cleanup_conf && path.exists() {
+			debug!(?path, "Removing dracut drop-in");
+			std::fs::remove_file(path)?;
+		}
+		Ok(())
+	}
+}
+
+/// Options for the kernel modules in `/usr/lib/modules/<version>/kernel`
+#[derive(Deserialize, Debug, Clone, Serialize, Default)]
+pub struct KernelModulesConfig {
+	/// Modules or directories of modules to remove, relative to `kernel/`, e.g.
+	/// `drivers/gpu/drm/nouveau`
+	#[serde(default)]
+	pub drop: Vec<PathBuf>,
+	/// Modules or directories to keep inside the dropped ones
+	#[serde(default)]
+	pub keep: Vec<PathBuf>,
+	/// Sign the remaining modules, for Secure Boot with a kernel trusting this key
+	#[serde(default)]
+	pub sign: Option<ModuleSigning>,
+	/// Run `depmod` again after the post scripts and `copy_files`, for scripts installing
+	/// out-of-tree modules (akmods, DKMS). Disk images also get their initramfs regenerated,
+	/// live media always gets a new one
+	#[serde(default)]
+	pub depmod_after_scripts: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Serialize)]
+pub struct ModuleSigning {
+	/// Private key, on the host
+	pub key: PathBuf,
+	/// X.509 certificate of the key, on the host
+	pub cert: PathBuf,
+	/// Hash algorithm, defaults to `sha256`
+	#[serde(default)]
+	pub hash: Option<String>,
+}
+
+impl KernelModulesConfig {
+	/// Strips and signs the modules of every kernel in the chroot, then runs `depmod`
+	pub fn apply(&self, chroot: &Path) -> Result<()> {
+		if self.drop.is_empty() && self.sign.is_none() {
+			return Ok(());
+		}
+		for kver in Self::kernel_versions(chroot)? {
+			info!(kver, "Processing kernel modules");
+			let dir = chroot.join("usr/lib/modules").join(&kver).join("kernel");
+			self.strip(&dir)?;
+			if let Some(sign) = &self.sign {
+				sign.sign_all(chroot, &kver, &dir)?;
+			}
+			Self::depmod(chroot, &kver)?;
+		}
+		Ok(())
+	}
+
+	/// Versions of the kernels installed in the chroot, from `/usr/lib/modules`
+	pub fn kernel_versions(chroot: &Path) -> Result<Vec<String>> {
+		let mut kvers = vec![];
+		for entry in std::fs::read_dir(chroot.join("usr/lib/modules"))? {
+			let entry = entry?;
+			if entry.file_type()?.is_dir() {
+				kvers.push(entry.file_name().to_string_lossy().into_owned());
+			}
+		}
+		kvers.sort();
+		Ok(kvers)
+	}
+
+	/// Builds the `depmod` command regenerating `modules.dep` of kernel `kver` in the chroot
+	pub fn depmod_cmd(chroot: &Path, kver: &str) -> std::process::Command {
+		let mut cmd = std::process::Command::new("depmod");
+		cmd.arg("-a").arg("-b").arg(chroot).arg(kver);
+		cmd
+	}
+
+	pub fn depmod(chroot: &Path, kver: &str) -> Result<()> {
+		debug!(kver, "Running depmod");
+		let status = Self::depmod_cmd(chroot, kver).status()?;
+		if !status.success() {
+			color_eyre::eyre::bail!("depmod failed for {kver} with {status}");
+		}
+		Ok(())
+	}
+
+	/// Removes the dropped modules from a kernel's `kernel/` directory, except the kept ones
+	pub fn strip(&self, kernel_dir: &Path) -> Result<()> {
+		for drop in &self.drop {
+			let keep: Vec<_> = self.keep.iter().filter(|k| k.starts_with(drop)).collect();
+			Self::remove_except(&kernel_dir.join(drop), drop, &keep)?;
+		}
+		Ok(())
+	}
+
+	fn remove_except(path: &Path, rel: &Path, keep: &[&PathBuf]) -> Result<()> {
+		if keep.iter().any(|k| rel.starts_with(k)) || !path.exists() {
+			return Ok(());
+		}
+		if !path.is_dir() || !keep.iter().any(|k| k.starts_with(rel)) {
+			debug!(?path, "Removing kernel modules");
+			if path.is_dir() {
+				std::fs::remove_dir_all(path)?;
+			} else {
+				std::fs::remove_file(path)?;
+			}
+			return Ok(());
+		}
+		for entry in std::fs::read_dir(path)? {
+			let name = entry?.file_name();
+			Self::remove_except(&path.join(&name), &rel.join(&name), keep)?;
+		}
+		Ok(())
+	}
+}
+
+impl ModuleSigning {
+	/// Signs every module in `dir` with the kernel's `sign-file` from `kernel-devel`,
+	/// decompressing and recompressing `.ko.xz` and `.ko.zst` modules
+	pub fn sign_all(&self, chroot: &Path, kver: &str, dir: &Path) -> Result<()> {
+		let sign_file = chroot.join("usr/src/kernels").join(kver).join("scripts/sign-file");
+		if !sign_file.exists() {
+			color_eyre::eyre::bail!(
+				"{} not found, kernel-devel is needed to sign modules",
+				sign_file.display()
+			);
+		}
+		let (key, cert) = (&self.key, &self.cert);
+		let hash = self.hash.as_deref().unwrap_or("sha256");
+		for path in glob::glob(&format!("{}/**/*.ko*", dir.display()))? {
+			let path = path?;
+			let name = path.to_string_lossy().into_owned();
+			if let Some(ko) = name.strip_suffix(".xz").filter(|n| n.ends_with(".ko")) {
+				cmd_lib::run_cmd!(
+					xz -d $path;
+					$sign_file $hash $key $cert $ko;
+					xz -f --check=crc32 $ko;
+				)?;
+			} else if let Some(ko) = name.strip_suffix(".zst").filter(|n| n.ends_with(".ko")) {
+				cmd_lib::run_cmd!(
+					zstd -q -d --rm $path;
+					$sign_file $hash $key $cert $ko;
+					zstd -q --rm $ko;
+				)?;
+			} else if name.ends_with(".ko") {
+				cmd_lib::run_cmd!($sign_file $hash $key $cert $path)?;
+			}
+		}
+		Ok(())
 	}
 }
 
@@ -221,6 +1320,9 @@ pub struct Script {
 	/// Default 50, the higher, the later the script executes
 	#[serde(default = "script_default_priority")]
 	pub priority: i32,
+	/// Log a warning instead of failing the build if the script fails
+	#[serde(default)]
+	pub ignore_errors: bool,
 }
 
 impl Script {
@@ -274,6 +1376,179 @@ fn test_dev_name() {
 pub struct PartitionLayout {
 	pub size: Option<ByteSize>,
 	pub partitions: Vec<Partition>,
+	/// Rely on the Discoverable Partitions Specification to mount `/`, the ESP and `/home`
+	/// instead of generating fstab entries.
+	///
+	/// Partition types are set from their mountpoints, and the root partition is marked `grow-fs`.
+	#[serde(default)]
+	pub discoverable: bool,
+	/// Write a JSON summary of the created partitions to this path
+	#[serde(default)]
+	pub summary_json: Option<PathBuf>,
+	/// Add a `root=` argument for the root partition to the kernel command line
+	#[serde(default)]
+	pub root_ref: Option<RootRef>,
+	/// Boot with U-Boot on single board computers, see [`UBootConfig`]
+	#[serde(default)]
+	pub uboot: Option<UBootConfig>,
+	/// Write `/boot/extlinux/extlinux.conf` for distro boot, always done with `uboot`
+	#[serde(default)]
+	pub extlinux: Option<ExtlinuxConfig>,
+	/// Device trees for the board, relative to the kernel's dtb directory
+	/// (`/usr/lib/modules/<kver>/dtb` or `/boot/dtb`), like `broadcom/bcm2711-rpi-4-b.dtb`.
+	///
+	/// They are copied to `/boot/dtb`, and to the firmware partition with `uboot`. The first one
+	/// is the default `extlinux.fdt`.
+	#[serde(default)]
+	pub dtbs: Vec<PathBuf>,
+	/// Partition alignment, either a `parted --align` mode (`optimal`, `minimal`, `cylinder`,
+	/// `none`) or a size like `4MiB` to start every partition on a multiple of, such as a flash
+	/// erase block. Defaults to parted's own (`optimal`).
+	#[serde(default)]
+	pub alignment: Option<Alignment>,
+	/// Comment at the top of the generated `/etc/fstab` instead of Katsu's, one `#` line per
+	/// line of text
+	#[serde(default)]
+	pub fstab_header: Option<String>,
+	/// Compression of a copy of the disk image written next to it, like `katsu.img.xz`. The raw
+	/// image is kept, so this needs room for both. Unset only writes the raw image
+	#[serde(default)]
+	pub compression: Option<Compression>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Alignment {
+	Mode(AlignMode),
+	Size(ByteSize),
+}
+
+/// `parted --align` modes
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlignMode {
+	None,
+	Cylinder,
+	Minimal,
+	Optimal,
+}
+
+impl Alignment {
+	/// `parted --align` arguments, exact offsets from a size alignment are used as they are
+	pub fn parted_args(&self) -> Vec<String> {
+		let mode = match self {
+			Self::Mode(AlignMode::None) | Self::Size(_) => "none",
+			Self::Mode(AlignMode::Cylinder) => "cylinder",
+			Self::Mode(AlignMode::Minimal) => "minimal",
+			Self::Mode(AlignMode::Optimal) => "optimal",
+		};
+		vec!["--align".to_string(), mode.to_string()]
+	}
+}
+
+/// Options for `/boot/extlinux/extlinux.conf`, the boot menu read by U-Boot's distro boot
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq, Default)]
+pub struct ExtlinuxConfig {
+	/// Device tree blob to pass to the kernel, relative to `/boot`
+	#[serde(default)]
+	pub fdt: Option<PathBuf>,
+}
+
+/// U-Boot for single board computers like the Raspberry Pi
+///
+/// The board's U-Boot binary is copied from Fedora's `uboot-images-armv8` package (which has to
+/// be installed in the image) to the firmware partition, and `extlinux.conf` is written for
+/// U-Boot's distro boot. Set `root_ref` so the kernel can find the root partition.
+/// Boards needing U-Boot written to raw disk sectors (with an SPL) aren't supported.
+#[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct UBootConfig {
+	/// Board ID, the directory name in `/usr/share/uboot`, like `rpi_arm64` or `rpi_4`
+	pub board: String,
+	/// Mountpoint of the partition the board firmware loads U-Boot from, defaults to the ESP
+	#[serde(default = "uboot_default_firmware_dir")]
+	pub firmware_dir: PathBuf,
+}
+
+fn uboot_default_firmware_dir() -> PathBuf {
+	PathBuf::from("/boot/efi")
+}
+
+impl UBootConfig {
+	/// Where `uboot-images-armv8` installs the binaries, relative to the chroot
+	pub const IMAGES_DIR: &'static str = "usr/share/uboot";
+
+	/// The U-Boot binary for the board, relative to the chroot
+	pub fn binary(&self) -> Result<PathBuf> {
+		let board = &self.board;
+		if board.is_empty()
+			|| !board.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+		{
+			color_eyre::eyre::bail!("Invalid U-Boot board ID `{board}`");
+		}
+		Ok(Path::new(Self::IMAGES_DIR).join(board).join("u-boot.bin"))
+	}
+}
+
+/// How the root partition is referred to on the kernel command line
+#[derive(Deserialize, Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RootRef {
+	/// `root=PARTUUID=`, the GPT partition UUID, which doesn't need an initramfs to resolve
+	Partuuid,
+	/// `root=UUID=`, the filesystem UUID
+	Uuid,
+}
+
+/// A partition as created on disk, see [`PartitionLayout::summary`]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PartitionSummary {
+	/// Partition number, starting from 1
+	pub index: usize,
+	/// Device node of the partition
+	pub device: String,
+	/// GPT partition type GUID
+	pub type_uuid: String,
+	/// Size in bytes, if known
+	pub size: Option<u64>,
+	pub filesystem: String,
+	pub label: Option<String>,
+	/// Filesystem UUID, only known after formatting
+	pub uuid: Option<String>,
+	/// GPT partition UUID, only known after partitioning
+	pub partuuid: Option<String>,
+	/// dm-verity root hash, for partitions with [`Partition::verity`] set after the build
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub roothash: Option<String>,
+}
+
+/// Where a partition goes on disk, see [`PartitionLayout::plan`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionPlan<'a> {
+	/// Partition number, starting from 1
+	pub index: usize,
+	/// Start offset in bytes
+	pub start: u64,
+	/// End offset in bytes, `None` if the partition fills the rest of the disk
+	pub end: Option<u64>,
+	pub partition: &'a Partition,
+}
+
+impl PartitionPlan<'_> {
+	/// Start offset as passed to `parted`
+	fn start_string(&self) -> String {
+		if self.start == 0 {
+			// create partition at start of disk
+			"0".to_string()
+		} else {
+			ByteSize::b(self.start).to_string_as(true).replace(' ', "")
+		}
+	}
+
+	/// End offset as passed to `parted`
+	fn end_string(&self) -> String {
+		self.end
+			.map_or("100%".to_string(), |end| ByteSize::b(end).to_string_as(true).replace(' ', ""))
+	}
 }
 
 #[derive(Serialize, Debug)]
@@ -286,6 +1561,18 @@ struct TplFstabEntry<'a> {
 
 #[allow(dead_code)]
 impl PartitionLayout {
+	/// The `extlinux.conf` options, if one should be written
+	pub fn extlinux_config(&self) -> Option<ExtlinuxConfig> {
+		let mut extlinux = self
+			.extlinux
+			.clone()
+			.or_else(|| self.uboot.as_ref().map(|_| ExtlinuxConfig::default()))?;
+		if extlinux.fdt.is_none() {
+			extlinux.fdt = self.dtbs.first().map(|dtb| Path::new("dtb").join(dtb));
+		}
+		Some(extlinux)
+	}
+
 	pub fn new() -> Self {
 		Self::default()
 	}
@@ -356,6 +1643,7 @@ impl PartitionLayout {
 		ordered
 	}
 
+	#[tracing::instrument(target = "katsu::partition", skip(self))]
 	pub fn mount_to_chroot(&self, disk: &Path, chroot: &Path) -> Result<()> {
 		// mount partitions to chroot
 
@@ -405,15 +1693,66 @@ impl PartitionLayout {
 		Ok(())
 	}
 
+	/// Partition type for the partition, taking [`PartitionLayout::discoverable`] into account
+	pub fn partition_type(&self, part: &Partition) -> PartitionType {
+		if !self.discoverable {
+			return part.partition_type.clone();
+		}
+		let dps_type = match (part.mountpoint.as_str(), &part.partition_type) {
+			// arch-specific root types are already discoverable
+			("/", PartitionType::RootArm64 | PartitionType::RootX86_64) => {
+				return part.partition_type.clone()
+			},
+			("/", _) => PartitionType::Root,
+			("/efi" | "/boot/efi", _) => PartitionType::Esp,
+			("/boot", _) => PartitionType::Xbootldr,
+			("/home", _) => PartitionType::Home,
+			_ => return part.partition_type.clone(),
+		};
+		if part.partition_type != dps_type {
+			warn!(
+				mountpoint = part.mountpoint,
+				?dps_type,
+				"Overriding partition type for discoverable partitions"
+			);
+		}
+		dps_type
+	}
+
+	/// GPT attribute flags for the partition, taking [`PartitionLayout::discoverable`] into account
+	pub fn partition_flags(&self, part: &Partition) -> Vec<PartitionFlag> {
+		let mut flags = part.flags.clone().unwrap_or_default();
+		if self.discoverable && part.mountpoint == "/" && !flags.contains(&PartitionFlag::GrowFs) {
+			flags.push(PartitionFlag::GrowFs);
+		}
+		flags
+	}
+
 	/// Generate fstab entries for the partitions
+	#[tracing::instrument(target = "katsu::partition", skip(self))]
 	pub fn fstab(&self, chroot: &Path) -> Result<String> {
 		// sort partitions by mountpoint
 		let ordered = self.sort_partitions();
 
 		crate::prepend_comment!(PREPEND: "/etc/fstab", "static file system information.", katsu::config::PartitionLayout::fstab);
+		let header = match &self.fstab_header {
+			Some(header) => {
+				let lines: String = header
+					.lines()
+					.map(|l| format!("# {l}").trim_end().to_string() + "\n")
+					.collect();
+				format!("#\n{lines}\n")
+			},
+			None => PREPEND.to_string(),
+		};
 
 		let mut entries = vec![];
 
+		if self.discoverable {
+			// systemd-gpt-auto-generator mounts everything for us
+			return Ok(crate::tpl!("fstab.tera" => { PREPEND: header, entries }));
+		}
+
 		ordered.iter().try_for_each(|(_, part)| -> Result<()> {
 			if part.filesystem != "none" {
 				let mp = PathBuf::from(&part.mountpoint).to_string_lossy().to_string();
@@ -434,10 +1773,145 @@ impl PartitionLayout {
 
 		trace!(?entries, "fstab entries generated");
 
-		Ok(crate::tpl!("fstab.tera" => { PREPEND, entries }))
+		Ok(crate::tpl!("fstab.tera" => { PREPEND: header, entries }))
+	}
+
+	/// Size of the disk, failing with a hint on how to set it if it's missing
+	pub fn disk_size(&self) -> Result<ByteSize> {
+		if let Some(size) = self.size {
+			return Ok(size);
+		}
+		let example = match self.min_size_mib() {
+			Some(min) => format!(
+				"All partitions have a fixed size, so the disk needs at least {min}MiB:\n\n  disk:\n    size: {min}MiB"
+			),
+			None => "For example:\n\n  disk:\n    size: 8GiB\n    partitions:\n      ...".to_string(),
+		};
+		color_eyre::eyre::bail!(
+			"Disk size not specified. Set `size` in the `disk` section of the manifest, the last \
+			 partition can leave out its own `size` to take the rest of the disk.\n\n{example}"
+		)
+	}
+
+	/// Smallest disk in MiB that fits the partitions, if they all have a fixed size
+	fn min_size_mib(&self) -> Option<u64> {
+		let plan = self.plan();
+		if plan.iter().any(|p| p.end.is_none()) {
+			return None;
+		}
+		let end = plan.last().and_then(|p| p.end).unwrap_or(0);
+		// room for the alignment before the first partition and the backup GPT at the end
+		Some(end.div_ceil(1024 * 1024) + 2)
+	}
+
+	/// Computes where each partition starts and ends, without touching any disk
+	pub fn plan(&self) -> Vec<PartitionPlan<'_>> {
+		let align = match self.alignment {
+			Some(Alignment::Size(size)) if size.as_u64() > 0 => Some(size.as_u64()),
+			_ => None,
+		};
+		// with a size alignment the first partition starts after one alignment unit instead of
+		// wherever parted puts it
+		let mut last_end = align.unwrap_or(0);
+		self.partitions
+			.iter()
+			.enumerate()
+			.map(|(i, partition)| {
+				let start = align.map_or(last_end, |a| last_end.div_ceil(a) * a);
+				let end = partition.size.map(|size| {
+					last_end = start + size.as_u64();
+					last_end
+				});
+				PartitionPlan { index: i + 1, start, end, partition }
+			})
+			.collect()
+	}
+
+	/// Checks that every partition has room on the disk: sizes can't be zero, only the last
+	/// partition can fill the rest of the disk, and the partitions have to fit in `size`
+	pub fn validate_sizes(&self) -> Result<()> {
+		let last = self.partitions.len();
+		for plan in self.plan() {
+			let name = format!("Partition {} ({})", plan.index, plan.partition.mountpoint);
+			match plan.partition.size {
+				Some(size) if size.as_u64() == 0 => {
+					color_eyre::eyre::bail!("{name} has a size of 0")
+				},
+				None if plan.index != last => color_eyre::eyre::bail!(
+					"{name} has no size, only the last partition can fill the rest of the disk"
+				),
+				_ => {},
+			}
+			let (Some(end), Some(disk)) = (plan.end, self.size) else { continue };
+			if end > disk.as_u64() {
+				color_eyre::eyre::bail!(
+					"{name} ends at {}, past the end of the {} disk",
+					ByteSize::b(end).to_string_as(true),
+					disk.to_string_as(true)
+				);
+			}
+		}
+		Ok(())
+	}
+
+	/// Renders [`PartitionLayout::plan`] as a table for `--plan`
+	pub fn plan_table(&self, target_arch: &str) -> String {
+		let fmt = |b: u64| ByteSize::b(b).to_string_as(true);
+		let disk_end = self.size.map(|s| s.as_u64());
+		let mut table = format!(
+			"{:<3} {:<11} {:<11} {:<11} {:<36} {:<10} {:<12} {}\n",
+			"#", "START", "END", "SIZE", "TYPE", "FS", "LABEL", "FLAGS"
+		);
+		for plan in self.plan() {
+			let part = plan.partition;
+			let end = plan.end.or(disk_end);
+			let flags = self.partition_flags(part);
+			let flags = if flags.is_empty() {
+				"-".to_string()
+			} else {
+				flags.iter().map(|f| format!("{f:?}")).collect::<Vec<_>>().join(",")
+			};
+			table += &format!(
+				"{:<3} {:<11} {:<11} {:<11} {:<36} {:<10} {:<12} {}\n",
+				plan.index,
+				fmt(plan.start),
+				end.map_or("100%".to_string(), fmt),
+				end.map_or("-".to_string(), |end| fmt(end - plan.start)),
+				self.partition_type(part).uuid(target_arch),
+				part.filesystem,
+				part.label.as_deref().unwrap_or("-"),
+				flags,
+			);
+		}
+		table
+	}
+
+	/// Describes the partitions that [`PartitionLayout::apply`] creates on `disk`
+	pub fn summary(&self, disk: &Path, target_arch: &str) -> Vec<PartitionSummary> {
+		let disk_end = self.size.map(|s| s.as_u64());
+		self.plan()
+			.into_iter()
+			.map(|plan| {
+				let part = plan.partition;
+				PartitionSummary {
+					index: plan.index,
+					device: partition_name(&disk.to_string_lossy(), plan.index),
+					type_uuid: self.partition_type(part).uuid(target_arch),
+					// the last partition takes the rest of the disk
+					size: plan.end.or(disk_end).map(|end| end.saturating_sub(plan.start)),
+					filesystem: part.filesystem.clone(),
+					label: part.label.clone(),
+					uuid: None,
+					partuuid: None,
+					roothash: None,
+				}
+			})
+			.collect()
 	}
 
-	pub fn apply(&self, disk: &PathBuf, target_arch: &str) -> Result<()> {
+	/// Partitions and formats the disk, returning a summary of the created partitions
+	#[tracing::instrument(target = "katsu::partition", skip(self))]
+	pub fn apply(&self, disk: &PathBuf, target_arch: &str) -> Result<Vec<PartitionSummary>> {
 		// This is a destructive operation, so we need to make sure we don't accidentally wipe the wrong disk
 
 		info!("Applying partition layout to disk: {disk:#?}");
@@ -449,41 +1923,31 @@ impl PartitionLayout {
 		cmd_lib::run_cmd!(parted -s $disk mklabel gpt 2>&1)?;
 
 		// create partitions
-		self.partitions.iter().try_fold((1, 0), |(i, mut last_end), part| {
+		self.plan().iter().try_for_each(|plan| {
+			let (i, part) = (plan.index, plan.partition);
 			let devname = partition_name(&disk.to_string_lossy(), i);
 			trace!(devname, "Creating partition {i}: {part:#?}");
 
 			let span = tracing::trace_span!("partition", devname);
 			let _enter = span.enter();
 
-			let start_string = if i == 1 {
-				// create partition at start of disk
-				"0".to_string()
-			} else {
-				// create partition after last partition
-				ByteSize::b(last_end).to_string_as(true).replace(' ', "")
-			};
-
-			let end_string = part.size.map_or("100%".to_string(), |size| {
-				// create partition with size
-				last_end += size.as_u64();
-
-				// remove space for partition table
-				ByteSize::b(last_end).to_string_as(true).replace(' ', "")
-			});
+			let start_string = plan.start_string();
+			let end_string = plan.end_string();
 
 			// not going to change this for now though, but will revisit
 			debug!(start = start_string, end = end_string, "Creating partition");
-			trace!("parted -s {disk:?} mkpart primary fat32 {start_string} {end_string}");
-			cmd_lib::run_cmd!(parted -s $disk mkpart primary fat32 $start_string $end_string 2>&1)?;
+			let align = self.alignment.map(|a| a.parted_args()).unwrap_or_default();
+			trace!("parted -s {align:?} {disk:?} mkpart primary fat32 {start_string} {end_string}");
+			cmd_lib::run_cmd!(parted -s $[align] $disk mkpart primary fat32 $start_string $end_string 2>&1)?;
 
-			let part_type_uuid = part.partition_type.uuid(target_arch);
+			let part_type_uuid = self.partition_type(part).uuid(target_arch);
 
 			debug!("Setting partition type");
 			trace!("parted -s {disk:?} type {i} {part_type_uuid}");
 			cmd_lib::run_cmd!(parted -s $disk type $i $part_type_uuid 2>&1)?;
 
-			if let Some(flags) = &part.flags {
+			let flags = self.partition_flags(part);
+			if !flags.is_empty() {
 				debug!("Setting partition attribute flags");
 
 				for flag in flags {
@@ -521,11 +1985,152 @@ impl PartitionLayout {
 				cmd_lib::run_cmd!(mkfs.$fsname $devname 2>&1)?;
 			}
 
-			Result::<_>::Ok((i + 1, last_end))
+			Result::<_>::Ok(())
 		})?;
 
+		let mut summary = self.summary(disk, target_arch);
+		for entry in &mut summary {
+			let device = &entry.device;
+			entry.partuuid = cmd_lib::run_fun!(blkid -s PARTUUID -o value $device).ok();
+			if entry.filesystem != "none" {
+				entry.uuid = cmd_lib::run_fun!(blkid -s UUID -o value $device).ok();
+			}
+		}
+
+		self.write_summary(&summary)?;
+
+		Ok(summary)
+	}
+
+	/// The `root=` kernel argument for [`PartitionLayout::root_ref`], from the summary returned by
+	/// [`PartitionLayout::apply`]
+	pub fn root_cmdline(&self, summary: &[PartitionSummary]) -> Result<Option<String>> {
+		let Some(root_ref) = self.root_ref else { return Ok(None) };
+		crate::bail_let!(Some(index) = self.get_index("/") => "No root partition to put on the kernel command line");
+		crate::bail_let!(Some(entry) = summary.iter().find(|e| e.index == index) => "Root partition missing from summary");
+		Ok(Some(match root_ref {
+			RootRef::Partuuid => {
+				let device = &entry.device;
+				crate::bail_let!(Some(partuuid) = &entry.partuuid => "Could not get the PARTUUID of {device}");
+				format!("root=PARTUUID={partuuid}")
+			},
+			RootRef::Uuid => {
+				let device = &entry.device;
+				crate::bail_let!(Some(uuid) = &entry.uuid => "Could not get the UUID of {device}");
+				format!("root=UUID={uuid}")
+			},
+		}))
+	}
+
+	/// Commands creating the [`Partition::snapshot`]s of the btrfs partitions mounted in `chroot`
+	pub fn snapshot_cmds(&self, chroot: &Path) -> Vec<std::process::Command> {
+		self.partitions
+			.iter()
+			.filter_map(|part| {
+				let snapshot = part.snapshot.as_ref()?;
+				if part.filesystem != "btrfs" {
+					warn!(mountpoint = part.mountpoint, "Snapshots need btrfs, skipping");
+					return None;
+				}
+				let source = chroot.join(part.mountpoint.trim_start_matches('/'));
+				let mut cmd = std::process::Command::new("btrfs");
+				cmd.args(["subvolume", "snapshot", "-r"]).arg(&source).arg(source.join(snapshot));
+				Some(cmd)
+			})
+			.collect()
+	}
+
+	/// Creates the initial [`Partition::snapshot`]s, after the root build
+	pub fn create_snapshots(&self, chroot: &Path) -> Result<()> {
+		for mut cmd in self.snapshot_cmds(chroot) {
+			let dest = PathBuf::from(cmd.get_args().last().unwrap());
+			info!(?dest, "Creating btrfs snapshot");
+			fs::create_dir_all(dest.parent().unwrap())?;
+			let status = cmd.status()?;
+			if !status.success() {
+				color_eyre::eyre::bail!("Failed to create btrfs snapshot {dest:?}: {status}");
+			}
+		}
+		Ok(())
+	}
+
+	/// Writes the summary to [`PartitionLayout::summary_json`], if set
+	pub fn write_summary(&self, summary: &[PartitionSummary]) -> Result<()> {
+		if let Some(path) = &self.summary_json {
+			info!(?path, "Writing partition summary");
+			crate::util::just_write(path, serde_json::to_string_pretty(summary)?)?;
+		}
 		Ok(())
 	}
+
+	/// Returns the layout with a hash partition inserted after every [`Partition::verity`] partition
+	pub fn with_verity(&self) -> Result<Self> {
+		let mut layout = self.clone();
+		layout.partitions.clear();
+		for part in &self.partitions {
+			layout.partitions.push(part.clone());
+			if !part.verity {
+				continue;
+			}
+			if part.mountpoint != "/" {
+				color_eyre::eyre::bail!(
+					"dm-verity is only supported for the root partition, not {}",
+					part.mountpoint
+				);
+			}
+			crate::bail_let!(Some(size) = part.size => "Verity partitions must have a fixed size");
+			layout.partitions.push(Partition {
+				label: part.label.as_ref().map(|l| format!("{l}-verity")),
+				partition_type: PartitionType::RootVerity,
+				flags: Some(vec![PartitionFlag::ReadOnly]),
+				size: Some(verity_hash_size(size)),
+				filesystem: "none".to_string(),
+				mountpoint: "-".to_string(),
+				subvolumes: vec![],
+				verity: false,
+				snapshot: None,
+			});
+		}
+		Ok(layout)
+	}
+
+	/// Computes the dm-verity hashes of the verity partitions, recording their root hashes in
+	/// `summary` and rewriting the summary file
+	///
+	/// The layout must come from [`PartitionLayout::with_verity`], and the partitions must be
+	/// unmounted since they are read-only afterwards.
+	#[tracing::instrument(target = "katsu::partition", skip(self, summary))]
+	pub fn format_verity(&self, disk: &Path, summary: &mut [PartitionSummary]) -> Result<()> {
+		let disk = disk.to_string_lossy();
+		for (i, part) in self.partitions.iter().enumerate().filter(|(_, p)| p.verity) {
+			let data = partition_name(&disk, i + 1);
+			let hash = partition_name(&disk, i + 2);
+			info!(data, hash, "Formatting dm-verity hash partition");
+			let out = cmd_lib::run_fun!(veritysetup format $data $hash 2>&1)?;
+			crate::bail_let!(Some(roothash) = parse_roothash(&out) => "Could not find the root hash in veritysetup output");
+			warn!(
+				roothash,
+				"{} is not verified until `roothash={roothash}` is added to the kernel command line",
+				part.mountpoint
+			);
+			summary[i].roothash = Some(roothash);
+		}
+		self.write_summary(summary)
+	}
+}
+
+/// Size of the dm-verity hash partition for a data partition of `size`
+///
+/// With SHA-256 and 4 KiB blocks the hash tree takes up a bit under 1/127 of the data,
+/// so 1/64 leaves plenty of room. Rounded up to whole MiB.
+fn verity_hash_size(size: ByteSize) -> ByteSize {
+	const MIB: u64 = 1024 * 1024;
+	ByteSize::b((size.as_u64() / 64).max(MIB).div_ceil(MIB) * MIB)
+}
+
+/// Gets the root hash from `veritysetup format` output
+fn parse_roothash(out: &str) -> Option<String> {
+	out.lines().find_map(|l| l.strip_prefix("Root hash:")).map(|h| h.trim().to_string())
 }
 
 #[test]
@@ -555,6 +2160,8 @@ fn test_partlay() {
 		filesystem: "efi".to_string(),
 		mountpoint: "/boot/efi".to_string(),
 		subvolumes: vec![],
+		verity: false,
+		snapshot: None,
 	});
 
 	partlay.add_partition(Partition {
@@ -565,6 +2172,8 @@ fn test_partlay() {
 		filesystem: "ext4".to_string(),
 		mountpoint: "/boot".to_string(),
 		subvolumes: vec![],
+		verity: false,
+		snapshot: None,
 	});
 
 	partlay.add_partition(Partition {
@@ -575,6 +2184,8 @@ fn test_partlay() {
 		filesystem: "ext4".to_string(),
 		mountpoint: "/".to_string(),
 		subvolumes: vec![],
+		verity: false,
+		snapshot: None,
 	});
 
 	for (i, part) in partlay.partitions.iter().enumerate() {
@@ -613,6 +2224,8 @@ fn test_partlay() {
 				filesystem: "ext4".to_string(),
 				mountpoint: "/".to_string(),
 				subvolumes: vec![],
+				verity: false,
+				snapshot: None,
 			},
 		),
 		(
@@ -625,6 +2238,8 @@ fn test_partlay() {
 				filesystem: "ext4".to_string(),
 				mountpoint: "/boot".to_string(),
 				subvolumes: vec![],
+				verity: false,
+				snapshot: None,
 			},
 		),
 		(
@@ -637,6 +2252,8 @@ fn test_partlay() {
 				filesystem: "efi".to_string(),
 				mountpoint: "/boot/efi".to_string(),
 				subvolumes: vec![],
+				verity: false,
+				snapshot: None,
 			},
 		),
 	];
@@ -668,6 +2285,10 @@ pub enum PartitionType {
 	Xbootldr,
 	/// Swap partition
 	Swap,
+	/// Home partition, mounted to /home
+	Home,
+	/// dm-verity hash partition for the root partition of the target architecture
+	RootVerity,
 	/// A generic partition that carries a Linux filesystem
 	LinuxGeneric,
 	/// MBR header partition for grub-install
@@ -689,11 +2310,17 @@ impl PartitionType {
 					_ => unimplemented!(),
 				}
 			},
+			PartitionType::RootVerity => match target_arch {
+				"x86_64" => "2c7357ed-ebd2-46d9-aec1-23d437ec2bf5",
+				"aarch64" => "df3300ce-d69f-4c92-978c-9bfb0f38d820",
+				_ => unimplemented!(),
+			},
 			PartitionType::RootArm64 => "b921b045-1df0-41c3-af44-4c6f280d3fae",
 			PartitionType::RootX86_64 => "4f68bce3-e8cd-4db1-96e7-fbcaf984b709",
 			PartitionType::Esp => "c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
 			PartitionType::Xbootldr => "bc13c2ff-59e6-4262-a352-b275fd6f7172",
 			PartitionType::Swap => "0657fd6d-a4ab-43c4-84e5-0933c84b4f4f",
+			PartitionType::Home => "933ac7e1-2eb4-4f13-b844-0e14e2aef915",
 			PartitionType::LinuxGeneric => "0fc63daf-8483-4772-8e79-3d69d8477de4",
 			PartitionType::BiosGrub => "21686148-6449-6E6F-744E-656564454649",
 			PartitionType::Guid(guid) => return guid.to_string(),
@@ -740,7 +2367,8 @@ pub struct Partition {
 	/// GPT partition attribute flags to add
 	// todo: maybe represent this as a bitflag number, parted consumes the positions so I'm doing this for now
 	pub flags: Option<Vec<PartitionFlag>>,
-	/// If not specified, the partition will be created at the end of the disk (100%)
+	/// If not specified, the partition fills the rest of the disk (100%), which is only allowed
+	/// for the last partition
 	pub size: Option<ByteSize>,
 	/// Filesystem of the partition
 	pub filesystem: String,
@@ -752,6 +2380,93 @@ pub struct Partition {
 	/// Will only be used if the filesystem is btrfs
 	#[serde(default)]
 	pub subvolumes: Vec<BtrfsSubvolume>,
+
+	/// Protect the partition with dm-verity, adding a hash partition right after it
+	///
+	/// Only supported for the root partition, which must have a fixed size. The root hash is
+	/// only known once the partition is sealed, after the bootloader config is written, so
+	/// Katsu doesn't add it to the kernel command line. Take it from `roothash` in
+	/// [`PartitionLayout::summary_json`] and add `roothash=` (or `systemd.verity_root_hash=`)
+	/// to the command line yourself, or the image boots without verifying anything.
+	#[serde(default)]
+	pub verity: bool,
+
+	/// Create a read-only btrfs snapshot of the partition at this path (relative to the
+	/// partition) after the build, as a baseline to roll back to. Use `.snapshots/1/snapshot`
+	/// for Snapper
+	#[serde(default)]
+	pub snapshot: Option<PathBuf>,
+}
+
+#[test]
+fn test_partition_summary() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		"size: 8GiB\npartitions:\n  - label: EFI\n    type: esp\n    size: 512MiB\n    filesystem: efi\n    mountpoint: /boot/efi\n  - label: root\n    type: root\n    filesystem: ext4\n    mountpoint: /\n",
+	)
+	.unwrap();
+
+	let summary = partlay.summary(Path::new("/dev/loop0"), "x86_64");
+	let json = serde_json::to_value(&summary).unwrap();
+	assert_eq!(
+		json,
+		serde_json::json!([
+			{
+				"index": 1,
+				"device": "/dev/loop0p1",
+				"type_uuid": "c12a7328-f81f-11d2-ba4b-00a0c93ec93b",
+				"size": ByteSize::mib(512).as_u64(),
+				"filesystem": "efi",
+				"label": "EFI",
+				"uuid": null,
+				"partuuid": null,
+			},
+			{
+				"index": 2,
+				"device": "/dev/loop0p2",
+				"type_uuid": "4f68bce3-e8cd-4db1-96e7-fbcaf984b709",
+				"size": ByteSize::gib(8).as_u64() - ByteSize::mib(512).as_u64(),
+				"filesystem": "ext4",
+				"label": "root",
+				"uuid": null,
+				"partuuid": null,
+			},
+		])
+	);
+}
+
+#[test]
+fn test_discoverable_partitions() {
+	let part = |partition_type, mountpoint: &str| Partition {
+		label: None,
+		partition_type,
+		flags: None,
+		size: None,
+		filesystem: "ext4".to_string(),
+		mountpoint: mountpoint.to_string(),
+		subvolumes: vec![],
+		verity: false,
+		snapshot: None,
+	};
+
+	let mut partlay = PartitionLayout::new();
+	partlay.discoverable = true;
+	partlay.add_partition(part(PartitionType::Esp, "/boot/efi"));
+	partlay.add_partition(part(PartitionType::LinuxGeneric, "/"));
+	partlay.add_partition(part(PartitionType::LinuxGeneric, "/home"));
+
+	let root = &partlay.partitions[1];
+	assert_eq!(partlay.partition_type(root).uuid("x86_64"), "4f68bce3-e8cd-4db1-96e7-fbcaf984b709");
+	assert_eq!(
+		partlay.partition_type(root).uuid("aarch64"),
+		"b921b045-1df0-41c3-af44-4c6f280d3fae"
+	);
+	assert_eq!(partlay.partition_flags(root), vec![PartitionFlag::GrowFs]);
+	assert_eq!(partlay.partition_type(&partlay.partitions[2]), PartitionType::Home);
+
+	// no findmnt/blkid calls are needed, so this works without a real chroot
+	let fstab = partlay.fstab(Path::new("/nonexistent")).unwrap();
+	assert!(!fstab.contains("UUID="));
+	assert!(fstab.contains("# <file system>"));
 }
 
 #[derive(Deserialize, Debug, Clone, Serialize, PartialEq, Eq)]
@@ -883,3 +2598,517 @@ impl Auth {
 
 // 	// let ass: Manifest = Manifest { import: vec!["recurse1.yaml", "recurse2.yaml"], distro: Some("RecursiveOS"), out_file: None, dnf: (), scripts: () }
 // }
+
+#[test]
+fn test_include_dir_script() {
+	let tmp = crate::util::TestDir::new("include");
+	let manifest_dir = tmp.join("manifest");
+	let include_dirs = [tmp.join("include")];
+	let include_dir = &include_dirs[0];
+	fs::create_dir_all(&manifest_dir).unwrap();
+	fs::create_dir_all(include_dir).unwrap();
+
+	fs::write(include_dir.join("shared.sh"), "#!/bin/sh\necho shared\n").unwrap();
+	fs::write(
+		manifest_dir.join("manifest.yaml"),
+		"builder: dnf\nscripts:\n  post:\n    - id: shared\n      file: shared.sh\n",
+	)
+	.unwrap();
+
+	let path = manifest_dir.join("manifest.yaml");
+	assert!(Manifest::load(&path, &[]).is_err());
+
+	let manifest = Manifest::load(&path, &include_dirs).unwrap();
+	let script = &manifest.scripts.post[0];
+	assert_eq!(script.file, Some(include_dir.join("shared.sh").canonicalize().unwrap()));
+	assert_eq!(script.load().as_deref(), Some("#!/bin/sh\necho shared\n"));
+}
+
+#[test]
+fn test_verity_partition() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		"size: 8GiB\npartitions:\n  - label: EFI\n    type: esp\n    size: 512MiB\n    filesystem: efi\n    mountpoint: /boot/efi\n  - label: root\n    type: root\n    size: 6GiB\n    filesystem: erofs\n    mountpoint: /\n    verity: true\n",
+	)
+	.unwrap();
+	let partlay = partlay.with_verity().unwrap();
+	assert_eq!(partlay.partitions.len(), 3);
+
+	let hash = &partlay.partitions[2];
+	assert_eq!(hash.label.as_deref(), Some("root-verity"));
+	assert_eq!(hash.filesystem, "none");
+	assert_eq!(hash.size, Some(ByteSize::mib(96)));
+
+	let summary = partlay.summary(Path::new("/dev/loop0"), "x86_64");
+	assert_eq!(summary[2].device, "/dev/loop0p3");
+	assert_eq!(summary[2].type_uuid, "2c7357ed-ebd2-46d9-aec1-23d437ec2bf5");
+
+	let out = "VERITY header information for /dev/loop0p3\nUUID:            \t1f2c4b6e\nHash type:       \t1\nRoot hash:      \t4392712ba01368efdf14b05c76f9e4df0d53664630b5d48632ed17a137f39076\n";
+	assert_eq!(
+		parse_roothash(out).as_deref(),
+		Some("4392712ba01368efdf14b05c76f9e4df0d53664630b5d48632ed17a137f39076")
+	);
+
+	// only the root partition can be protected for now
+	let mut home = partlay.partitions[1].clone();
+	home.mountpoint = "/home".to_string();
+	let mut layout = PartitionLayout::new();
+	layout.add_partition(home);
+	assert!(layout.with_verity().is_err());
+}
+
+#[test]
+fn test_keymap() {
+	let manifest: Manifest = serde_yaml::from_str("keymap: de-latin1\n").unwrap();
+	assert_eq!(manifest.vconsole_conf().as_deref(), Some("KEYMAP=de-latin1\n"));
+	assert!(manifest.xorg_keyboard_conf().unwrap().contains("Option \"XkbLayout\" \"de\"\n"));
+
+	let manifest: Manifest = serde_yaml::from_str("keymap: us\nx11_layout: us,ru\n").unwrap();
+	assert!(manifest.xorg_keyboard_conf().unwrap().contains("\"us,ru\""));
+
+	let manifest: Manifest = serde_yaml::from_str("builder: dnf\n").unwrap();
+	assert_eq!(manifest.vconsole_conf(), None);
+	assert_eq!(manifest.xorg_keyboard_conf(), None);
+}
+
+#[test]
+fn test_partition_plan() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		"size: 8GiB\npartitions:\n  - label: EFI\n    type: esp\n    size: 512MiB\n    filesystem: efi\n    mountpoint: /boot/efi\n  - label: boot\n    type: xbootldr\n    size: 1GiB\n    filesystem: ext4\n    mountpoint: /boot\n  - label: root\n    type: root\n    filesystem: ext4\n    mountpoint: /\n",
+	)
+	.unwrap();
+
+	let plan = partlay.plan();
+	let offsets: Vec<_> = plan.iter().map(|p| (p.index, p.start, p.end)).collect();
+	let (mib, gib) = (ByteSize::mib(1).as_u64(), ByteSize::gib(1).as_u64());
+	assert_eq!(
+		offsets,
+		[
+			(1, 0, Some(512 * mib)),
+			(2, 512 * mib, Some(512 * mib + gib)),
+			(3, 512 * mib + gib, None)
+		]
+	);
+	assert_eq!(plan[0].start_string(), "0");
+	assert_eq!(plan[1].start_string(), "512.0MiB");
+	assert_eq!(plan[2].end_string(), "100%");
+
+	let table = partlay.plan_table("x86_64");
+	let lines: Vec<_> = table.lines().collect();
+	assert_eq!(lines.len(), 4);
+	assert!(lines[1].starts_with("1   0 B         512.0 MiB   512.0 MiB   c12a7328-f81f-11d2-ba4b-00a0c93ec93b efi        EFI"));
+	assert!(lines[3].contains("1.5 GiB     8.0 GiB     6.5 GiB"));
+}
+
+#[test]
+fn test_partition_alignment() {
+	let mut partlay: PartitionLayout = serde_yaml::from_str(
+		"size: 8GiB\nalignment: 4MiB\npartitions:\n  - label: EFI\n    type: esp\n    size: 511MiB\n    filesystem: efi\n    mountpoint: /boot/efi\n  - label: root\n    type: root\n    filesystem: ext4\n    mountpoint: /\n",
+	)
+	.unwrap();
+	let mib = ByteSize::mib(1).as_u64();
+	let offsets: Vec<_> = partlay.plan().iter().map(|p| (p.start, p.end)).collect();
+	assert_eq!(offsets, [(4 * mib, Some(515 * mib)), (516 * mib, None)]);
+	assert_eq!(partlay.plan()[0].start_string(), "4.0MiB");
+	assert_eq!(partlay.alignment.unwrap().parted_args(), ["--align", "none"]);
+
+	partlay.alignment = serde_yaml::from_str("minimal").unwrap();
+	assert_eq!(partlay.alignment, Some(Alignment::Mode(AlignMode::Minimal)));
+	assert_eq!(partlay.plan()[0].start, 0);
+	assert_eq!(partlay.alignment.unwrap().parted_args(), ["--align", "minimal"]);
+}
+
+#[test]
+fn test_zram_config() {
+	let manifest: Manifest =
+		serde_yaml::from_str("zram:\n  size: ram / 4\n  compression_algorithm: zstd\n").unwrap();
+	let conf = manifest.zram.unwrap().config().unwrap();
+	assert!(conf.contains("[zram0]\nzram-size = ram / 4\ncompression-algorithm = zstd\n"));
+
+	let manifest: Manifest = serde_yaml::from_str("zram: {}\n").unwrap();
+	let conf = manifest.zram.unwrap().config().unwrap();
+	assert!(conf.contains("zram-size = min(ram / 2, 4096)\n"));
+	assert!(!conf.contains("compression-algorithm"));
+}
+
+#[test]
+fn test_dracut_conf() {
+	let tmp = crate::util::TestDir::new("dracut-conf");
+	let manifest: Manifest = serde_yaml::from_str(
+		"dracut:\n  conf: |\n    add_dracutmodules+=\" ostree \"\n  cleanup_conf: true\n",
+	)
+	.unwrap();
+	manifest.dracut.write_conf(&tmp).unwrap();
+	let conf = std::fs::read_to_string(tmp.join("etc/dracut.conf.d/90-katsu.conf")).unwrap();
+	assert!(conf.ends_with("add_dracutmodules+=\" ostree \"\n"));
+
+	manifest.dracut.cleanup(&tmp).unwrap();
+	assert!(!tmp.join(DracutConfig::CONF_PATH).exists());
+}
+
+#[test]
+fn test_hosts() {
+	let manifest: Manifest =
+		serde_yaml::from_str("hosts:\n  10.0.0.1: [gateway]\n  10.0.0.10: [nas, nas.lan]\n")
+			.unwrap();
+	assert_eq!(
+		manifest.hosts_file(DEFAULT_HOSTS),
+		format!("{DEFAULT_HOSTS}10.0.0.1 gateway\n10.0.0.10 nas nas.lan\n")
+	);
+	assert_eq!(
+		manifest.hosts_file("127.0.0.1 localhost"),
+		"127.0.0.1 localhost\n10.0.0.1 gateway\n10.0.0.10 nas nas.lan\n"
+	);
+}
+
+#[test]
+fn test_embed_manifest() {
+	let tmp = crate::util::TestDir::new("embed");
+	let manifest: Manifest = serde_yaml::from_str(
+		"distro: Ultramarine\nembed_manifest: /usr/lib/katsu/manifest.json\ndnf:\n  packages: [kernel]\n",
+	)
+	.unwrap();
+	manifest.write_embedded(&tmp).unwrap();
+	let embedded = std::fs::read_to_string(tmp.join("usr/lib/katsu/manifest.json")).unwrap();
+	assert_eq!(embedded, manifest.to_json().unwrap());
+	let json: serde_json::Value = serde_json::from_str(&embedded).unwrap();
+	assert_eq!(json["dnf"]["packages"], serde_json::json!(["kernel"]));
+}
+
+#[test]
+fn test_disk_size_hint() {
+	let mut partlay: PartitionLayout = serde_yaml::from_str(
+		"partitions:\n  - label: EFI\n    type: esp\n    size: 512MiB\n    filesystem: efi\n    mountpoint: /boot/efi\n  - label: root\n    type: root\n    filesystem: ext4\n    mountpoint: /\n",
+	)
+	.unwrap();
+	let err = partlay.disk_size().unwrap_err().to_string();
+	assert!(err.contains("Set `size` in the `disk` section"));
+	assert!(err.contains("leave out its own `size` to take the rest of the disk"));
+	assert!(err.contains("size: 8GiB"));
+
+	partlay.partitions[1].size = Some(ByteSize::gib(4));
+	let err = partlay.disk_size().unwrap_err().to_string();
+	assert!(err.contains("the disk needs at least 4610MiB"));
+	assert!(err.ends_with("size: 4610MiB"));
+
+	partlay.size = Some(ByteSize::gib(8));
+	assert_eq!(partlay.disk_size().unwrap(), ByteSize::gib(8));
+}
+
+#[test]
+fn test_random_seed() {
+	use std::os::unix::fs::PermissionsExt;
+	let tmp = crate::util::TestDir::new("random-seed");
+	let manifest: Manifest = serde_yaml::from_str("random_seed:\n  size: 256\n").unwrap();
+	manifest.random_seed.as_ref().unwrap().write(&tmp).unwrap();
+
+	let meta = std::fs::metadata(tmp.join(RandomSeedConfig::PATH)).unwrap();
+	assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+	assert_eq!(meta.len(), 256);
+	assert_eq!(RandomSeedConfig::default().size, 512);
+}
+
+#[test]
+fn test_uboot_binary() {
+	let disk: PartitionLayout =
+		serde_yaml::from_str("uboot:\n  board: rpi_arm64\npartitions: []\n").unwrap();
+	let uboot = disk.uboot.unwrap();
+	assert_eq!(uboot.binary().unwrap(), Path::new("usr/share/uboot/rpi_arm64/u-boot.bin"));
+	assert_eq!(uboot.firmware_dir, Path::new("/boot/efi"));
+
+	let uboot = UBootConfig { board: "../../etc".to_string(), ..uboot };
+	assert!(uboot.binary().is_err());
+}
+
+#[test]
+fn test_root_cmdline() {
+	let mut partlay: PartitionLayout = serde_yaml::from_str(
+		"size: 8GiB\nroot_ref: partuuid\npartitions:\n  - label: EFI\n    type: esp\n    size: 512MiB\n    filesystem: efi\n    mountpoint: /boot/efi\n  - label: root\n    type: root\n    filesystem: ext4\n    mountpoint: /\n",
+	)
+	.unwrap();
+	let mut summary = partlay.summary(Path::new("/dev/loop0"), "x86_64");
+	summary[1].partuuid = Some("6a1a0b3c-0f2e-4d5b-9a8c-7e6f5d4c3b2a".to_string());
+	summary[1].uuid = Some("0f4bc1ad-2b34-4c29-8b9f-1d3e5a7c9e0b".to_string());
+
+	assert_eq!(
+		partlay.root_cmdline(&summary).unwrap().as_deref(),
+		Some("root=PARTUUID=6a1a0b3c-0f2e-4d5b-9a8c-7e6f5d4c3b2a")
+	);
+
+	partlay.root_ref = Some(RootRef::Uuid);
+	assert_eq!(
+		partlay.root_cmdline(&summary).unwrap().as_deref(),
+		Some("root=UUID=0f4bc1ad-2b34-4c29-8b9f-1d3e5a7c9e0b")
+	);
+
+	partlay.root_ref = None;
+	assert_eq!(partlay.root_cmdline(&summary).unwrap(), None);
+}
+
+#[test]
+fn test_btrfs_snapshot() {
+	let partlay: PartitionLayout = serde_yaml::from_str(
+		"size: 8GiB\npartitions:\n  - label: EFI\n    type: esp\n    size: 512MiB\n    filesystem: efi\n    mountpoint: /boot/efi\n    snapshot: .snapshots/1/snapshot\n  - label: root\n    type: root\n    filesystem: btrfs\n    mountpoint: /\n    snapshot: .snapshots/1/snapshot\n",
+	)
+	.unwrap();
+
+	// the EFI partition is not btrfs, so only the root gets a snapshot
+	let cmds = partlay.snapshot_cmds(Path::new("/katsu-work/chroot"));
+	assert_eq!(cmds.len(), 1);
+	assert_eq!(cmds[0].get_program(), "btrfs");
+	let args: Vec<_> = cmds[0].get_args().collect();
+	assert_eq!(
+		args,
+		[
+			"subvolume",
+			"snapshot",
+			"-r",
+			"/katsu-work/chroot/",
+			"/katsu-work/chroot/.snapshots/1/snapshot"
+		]
+	);
+}
+
+#[test]
+fn test_validate() {
+	let manifest: Manifest = serde_yaml::from_str(
+		"disk:\n  size: 8GiB\n  partitions:\n    - label: EFI\n      type: esp\n      size: 512MiB\n      filesystem: efi\n      mountpoint: /boot/efi\n",
+	)
+	.unwrap();
+	assert!(manifest.validate(OutputFormat::Iso).is_ok());
+	let err = manifest.validate(OutputFormat::DiskImage).unwrap_err();
+	assert_eq!(err.to_string(), "Disk layout has no partition mounted at `/`");
+
+	let manifest: Manifest = serde_yaml::from_str(
+		"bootloader: syslinux\ndisk:\n  size: 8GiB\n  partitions:\n    - type: root\n      filesystem: ext4\n      mountpoint: /\n",
+	)
+	.unwrap();
+	assert!(manifest.validate(OutputFormat::Iso).is_ok());
+	let err = manifest.validate(OutputFormat::DiskImage).unwrap_err();
+	assert_eq!(err.to_string(), SYSLINUX_NO_DISK);
+
+	let manifest: Manifest = serde_yaml::from_str(
+		"scripts:\n  post:\n    - id: a\n      inline: 'true'\n      needs: [b]\n",
+	)
+	.unwrap();
+	let err = manifest.validate(OutputFormat::Iso).unwrap_err();
+	assert_eq!(err.to_string(), "Script `b` required by `a` not found in scripts.post");
+}
+
+#[test]
+fn test_compression_defaults() {
+	let iso = Compression::default_for(OutputFormat::Iso);
+	let disk = Compression::default_for(OutputFormat::DiskImage);
+	assert_eq!(iso, Compression { algorithm: Compressor::Zstd, level: Some(19) });
+	assert_eq!(disk, Compression { algorithm: Compressor::None, level: None });
+	assert_eq!(Compression::default_for(OutputFormat::Folder).algorithm, Compressor::None);
+	assert_eq!(iso.mksquashfs_args(), ["-comp", "zstd", "-Xcompression-level", "19"]);
+	assert_eq!(iso.mkfs_erofs_args().unwrap(), ["-zzstd,19"]);
+
+	// algorithms without a level get their default level for the output
+	let manifest: Manifest = serde_yaml::from_str("compression:\n  algorithm: zstd\n").unwrap();
+	assert_eq!(manifest.compression(OutputFormat::Iso), iso);
+	let manifest: Manifest = serde_yaml::from_str("compression:\n  algorithm: lzo\n").unwrap();
+	assert_eq!(
+		manifest.compression(OutputFormat::Iso).mksquashfs_args(),
+		["-comp", "lzo", "-Xcompression-level", "8"]
+	);
+	let manifest: Manifest =
+		serde_yaml::from_str("disk:\n  compression:\n    algorithm: zstd\n  partitions: []\n")
+			.unwrap();
+	assert_eq!(manifest.compression(OutputFormat::DiskImage).level, Some(19));
+	assert_eq!(Compression::default_level(Compressor::Xz, OutputFormat::DiskImage), Some(9));
+	assert_eq!(Compression::default_level(Compressor::Xz, OutputFormat::Iso), None);
+
+	let manifest: Manifest = serde_yaml::from_str("compression:\n  algorithm: lz4\n").unwrap();
+	assert_eq!(manifest.compression(OutputFormat::Iso).mksquashfs_args(), ["-comp", "lz4", "-Xhc"]);
+	assert!(manifest.validate(OutputFormat::Iso).is_ok());
+
+	let manifest: Manifest =
+		serde_yaml::from_str("compression:\n  algorithm: xz\n  level: 9\n").unwrap();
+	let err = manifest.validate(OutputFormat::Iso).unwrap_err();
+	assert_eq!(err.to_string(), "xz compression doesn't take a level");
+	let manifest: Manifest =
+		serde_yaml::from_str("compression:\n  algorithm: gzip\n  level: 12\n").unwrap();
+	let err = manifest.validate(OutputFormat::Iso).unwrap_err();
+	assert_eq!(err.to_string(), "gzip compression level 12 is not between 1 and 9");
+
+	let manifest: Manifest = serde_yaml::from_str(
+		"compression:\n  algorithm: none\ndisk:\n  size: 8GiB\n  compression:\n    algorithm: lz4\n  partitions:\n    - type: root\n      filesystem: ext4\n      mountpoint: /\n",
+	)
+	.unwrap();
+	// the root image setting doesn't apply to disk images
+	assert_eq!(manifest.compression(OutputFormat::Iso).algorithm, Compressor::None);
+	let err = manifest.validate(OutputFormat::DiskImage).unwrap_err();
+	assert_eq!(err.to_string(), "Disk images can only be compressed with xz, zstd or gzip");
+}
+
+#[test]
+fn test_strip_kernel_modules() {
+	let tmp = crate::util::TestDir::new("kmods");
+	let drm = tmp.join("drivers/gpu/drm");
+	for module in ["nouveau/nouveau.ko.xz", "amdgpu/amdgpu.ko.xz", "i915/i915.ko.xz", "drm.ko.xz"] {
+		std::fs::create_dir_all(drm.join(module).parent().unwrap()).unwrap();
+		std::fs::write(drm.join(module), "").unwrap();
+	}
+	std::fs::create_dir_all(tmp.join("sound")).unwrap();
+
+	let modules: KernelModulesConfig = serde_yaml::from_str(
+		"drop: [drivers/gpu/drm, sound, drivers/missing]\nkeep: [drivers/gpu/drm/i915, drivers/gpu/drm/drm.ko.xz]\n",
+	)
+	.unwrap();
+	modules.strip(&tmp).unwrap();
+	assert!(!drm.join("nouveau").exists());
+	assert!(!drm.join("amdgpu").exists());
+	assert!(drm.join("i915/i915.ko.xz").exists());
+	assert!(drm.join("drm.ko.xz").exists());
+	assert!(!tmp.join("sound").exists());
+}
+
+#[test]
+fn test_media_size() {
+	let iso: IsoConfig = serde_yaml::from_str("media_size: dvd\n").unwrap();
+	let dvd = iso.media_size.unwrap();
+	assert_eq!(dvd.size().as_u64(), 4_700_000_000);
+	assert!(dvd.check(4_000_000_000, true).is_ok());
+	assert!(dvd.check(4_700_000_000, true).is_ok());
+	// too large only warns unless failing is asked for
+	assert!(dvd.check(4_800_000_000, false).is_ok());
+	let err = dvd.check(4_800_000_000, true).unwrap_err();
+	assert_eq!(err.to_string(), "ISO is 95.4 MiB larger than the media (dvd, 4.7 GB)");
+
+	let iso: IsoConfig = serde_yaml::from_str("media_size: 2GiB\n").unwrap();
+	assert_eq!(iso.media_size, Some(MediaSize::Size(ByteSize::gib(2))));
+}
+
+#[test]
+fn test_uncompressed_rootfs() {
+	let manifest: Manifest = serde_yaml::from_str("compression:\n  algorithm: store\n").unwrap();
+	let none = manifest.compression(OutputFormat::Iso);
+	assert_eq!(none.algorithm, Compressor::None);
+	assert_eq!(none.mksquashfs_args(), ["-noI", "-noD", "-noF", "-noX"]);
+	assert!(none.mkfs_erofs_args().unwrap().is_empty());
+}
+
+#[test]
+fn test_fstab_header() {
+	let layout = |extra: &str| -> PartitionLayout {
+		serde_yaml::from_str(&format!("discoverable: true\npartitions: []\n{extra}")).unwrap()
+	};
+	let fstab = layout("").fstab(Path::new("/nonexistent")).unwrap();
+	assert!(fstab.starts_with("#\n# /etc/fstab: static file system information.\n"));
+
+	let fstab =
+		layout("fstab_header: |\n  /etc/fstab for Ultramarine Linux\n\n  Generated by katsu\n")
+			.fstab(Path::new("/nonexistent"))
+			.unwrap();
+	assert!(fstab.starts_with(
+		"#\n# /etc/fstab for Ultramarine Linux\n#\n# Generated by katsu\n\n\n\n# <file system>"
+	));
+}
+
+#[test]
+fn test_tmpfs() {
+	let tmp = crate::util::TestDir::new("tmpfs");
+	std::fs::create_dir_all(tmp.join("etc")).unwrap();
+	std::fs::write(tmp.join("etc/fstab"), "UUID=1234\t/\text4\tdefaults\t0\t1").unwrap();
+
+	let manifest: Manifest = serde_yaml::from_str(
+		"tmpfs:\n  - path: /var/log\n    size: 64m\n    mode: '0755'\n  - path: /var/tmp\n",
+	)
+	.unwrap();
+	manifest.write_tmpfs(&tmp).unwrap();
+	assert_eq!(
+		std::fs::read_to_string(tmp.join("etc/fstab")).unwrap(),
+		"UUID=1234\t/\text4\tdefaults\t0\t1\ntmpfs\t/var/log\ttmpfs\tdefaults,size=64m,mode=0755\t0\t0\ntmpfs\t/var/tmp\ttmpfs\tdefaults\t0\t0\n"
+	);
+}
+
+#[test]
+fn test_environment() {
+	let manifest: Manifest =
+		serde_yaml::from_str("environment:\n  KATSU_TEST_ENVIRONMENT: from-manifest\n").unwrap();
+	manifest.apply_environment();
+	let out = cmd_lib::run_fun!(sh -c "echo $$KATSU_TEST_ENVIRONMENT");
+	// the process environment is shared by every test
+	std::env::remove_var("KATSU_TEST_ENVIRONMENT");
+	assert_eq!(out.unwrap(), "from-manifest");
+}
+
+#[test]
+fn test_validate_partition_sizes() {
+	let layout = |partitions: &str| -> PartitionLayout {
+		serde_yaml::from_str(&format!("size: 8GiB\npartitions:\n{partitions}")).unwrap()
+	};
+	let efi = "  - type: esp\n    size: 512MiB\n    filesystem: efi\n    mountpoint: /boot/efi\n";
+	let root = "  - type: root\n    filesystem: ext4\n    mountpoint: /\n";
+	assert!(layout(&format!("{efi}{root}")).validate_sizes().is_ok());
+
+	let zero = "  - type: esp\n    size: 0\n    filesystem: efi\n    mountpoint: /boot/efi\n";
+	let err = layout(&format!("{zero}{root}")).validate_sizes().unwrap_err();
+	assert_eq!(err.to_string(), "Partition 1 (/boot/efi) has a size of 0");
+
+	let err = layout(&format!("{root}{efi}")).validate_sizes().unwrap_err();
+	assert_eq!(
+		err.to_string(),
+		"Partition 1 (/) has no size, only the last partition can fill the rest of the disk"
+	);
+
+	let huge = "  - type: esp\n    size: 16GiB\n    filesystem: efi\n    mountpoint: /boot/efi\n";
+	let err = layout(&format!("{huge}{root}")).validate_sizes().unwrap_err();
+	assert_eq!(
+		err.to_string(),
+		"Partition 1 (/boot/efi) ends at 16.0 GiB, past the end of the 8.0 GiB disk"
+	);
+}
+
+#[test]
+fn test_depmod_after_scripts() {
+	let tmp = crate::util::TestDir::new("depmod");
+	for kver in ["6.8.0-1.fc40.x86_64", "6.9.2-1.fc40.x86_64"] {
+		std::fs::create_dir_all(tmp.join("usr/lib/modules").join(kver).join("extra")).unwrap();
+	}
+	std::fs::write(tmp.join("usr/lib/modules/README"), "").unwrap();
+
+	let kvers = KernelModulesConfig::kernel_versions(&tmp).unwrap();
+	assert_eq!(kvers, ["6.8.0-1.fc40.x86_64", "6.9.2-1.fc40.x86_64"]);
+	// depmod runs against the chroot with the resolved kernel version
+	let cmd = KernelModulesConfig::depmod_cmd(&tmp, &kvers[0]);
+	assert_eq!(cmd.get_program(), "depmod");
+	let args: Vec<_> = cmd.get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+	assert_eq!(args, ["-a", "-b", &tmp.to_string_lossy(), "6.8.0-1.fc40.x86_64"]);
+
+	let modules: KernelModulesConfig =
+		serde_yaml::from_str("depmod_after_scripts: true\n").unwrap();
+	assert!(modules.depmod_after_scripts);
+}
+
+#[test]
+fn test_volume_id_check() {
+	let iso = |yaml: &str| -> IsoConfig { serde_yaml::from_str(yaml).unwrap() };
+	assert!(iso("{}").check_volid().is_ok());
+	assert_eq!(iso("{}").get_volid(), "KATSU-LIVEOS");
+
+	let lower = iso("volume_id: Ultramarine 40.x86\n");
+	assert!(lower.check_volid().is_ok());
+	assert_eq!(lower.get_volid(), "Ultramarine 40.x86");
+
+	let normalized = iso("volume_id: Ultramarine 40.x86\nvolume_id_check: normalize\n");
+	assert!(normalized.check_volid().is_ok());
+	assert_eq!(normalized.get_volid(), "ULTRAMARINE_40_X86");
+
+	let strict = iso("volume_id: Ultramarine\nvolume_id_check: strict\n");
+	let err = strict.check_volid().unwrap_err();
+	assert_eq!(
+		err.to_string(),
+		"Volume ID `Ultramarine` can only have uppercase letters, digits, `_` and `-`"
+	);
+	assert!(iso("volume_id: ULTRAMARINE-40\nvolume_id_check: strict\n").check_volid().is_ok());
+
+	let long =
+		iso("volume_id: ULTRAMARINE-LINUX-40-KDE-PLASMA-X86_64\nvolume_id_check: normalize\n");
+	let err = long.check_volid().unwrap_err();
+	assert_eq!(
+		err.to_string(),
+		"Volume ID `ULTRAMARINE-LINUX-40-KDE-PLASMA-X86_64` is longer than the 32 characters ISO 9660 allows"
+	);
+}
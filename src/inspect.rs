@@ -0,0 +1,464 @@
+//! Reading back the artifacts Katsu produces, for `katsu --inspect`
+//!
+//! The on-disk formats are parsed directly so inspecting doesn't need root, loop devices or
+//! any tools on the host.
+use bytesize::ByteSize;
+use color_eyre::{eyre::bail, Result};
+use std::{
+	fmt,
+	fs::File,
+	io::{Read, Seek, SeekFrom},
+	path::Path,
+};
+use tracing::debug;
+
+/// ISO 9660 logical block size
+const ISO_BLOCK: u64 = 2048;
+/// Logical block size assumed for disk images
+const DISK_SECTOR: u64 = 512;
+/// Most GPT partition entries read, the spec's default table has 128
+const MAX_GPT_ENTRIES: usize = 1024;
+/// Signature in the PVD application area left by `implantisomd5`
+const ISO_MD5_MARKER: &[u8] = b"ISO MD5SUM = ";
+
+/// What was found in an artifact
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Artifact {
+	Iso(IsoInfo),
+	Disk(Vec<DiskPartition>),
+	Filesystem(Filesystem),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsoInfo {
+	pub volume_id: String,
+	/// Whether `implantisomd5` has been run on the image
+	pub md5_implanted: bool,
+	pub boot_entries: Vec<BootEntry>,
+	/// Root filesystem images found on the ISO, by path
+	pub rootfs: Vec<(String, Filesystem)>,
+}
+
+/// An El Torito boot catalog entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntry {
+	pub platform: &'static str,
+	pub bootable: bool,
+	pub lba: u32,
+	pub sectors: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskPartition {
+	pub index: usize,
+	pub type_uuid: String,
+	pub label: String,
+	pub start: u64,
+	pub size: u64,
+	pub filesystem: Option<Filesystem>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Filesystem {
+	pub kind: &'static str,
+	pub compression: Option<&'static str>,
+}
+
+/// Read up to `len` bytes at `offset`, less if the file ends first
+fn read_at(f: &mut File, offset: u64, len: usize) -> Result<Vec<u8>> {
+	f.seek(SeekFrom::Start(offset))?;
+	// `len` may come from a corrupted header, so only allocate what is actually read
+	let mut buf = vec![];
+	f.take(len as u64).read_to_end(&mut buf)?;
+	Ok(buf)
+}
+
+fn u16_le(buf: &[u8], at: usize) -> u16 {
+	u16::from_le_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_le(buf: &[u8], at: usize) -> u32 {
+	u32::from_le_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn u64_le(buf: &[u8], at: usize) -> u64 {
+	u64::from_le_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Detect the filesystem starting at `offset` from its superblock magic
+fn detect_fs(f: &mut File, offset: u64) -> Result<Option<Filesystem>> {
+	// btrfs has its superblock furthest in, at 64 KiB
+	let buf = read_at(f, offset, 65536 + 4096)?;
+	let at = |o: usize, magic: &[u8]| buf.get(o..o + magic.len()) == Some(magic);
+	let fs = |kind| Filesystem { kind, compression: None };
+
+	Ok(Some(if at(0, b"hsqs") {
+		let compression = match u16_le(&buf, 20) {
+			1 => "gzip",
+			2 => "lzma",
+			3 => "lzo",
+			4 => "xz",
+			5 => "lz4",
+			6 => "zstd",
+			_ => "unknown",
+		};
+		Filesystem { kind: "squashfs", compression: Some(compression) }
+	} else if at(1024, &0xE0F5_E1E2_u32.to_le_bytes()) {
+		fs("erofs")
+	} else if at(1080, &0xEF53_u16.to_le_bytes()) {
+		fs("ext4")
+	} else if at(0, b"XFSB") {
+		fs("xfs")
+	} else if at(65536 + 64, b"_BHRfS_M") {
+		fs("btrfs")
+	} else if at(82, b"FAT32   ") || at(54, b"FAT16   ") || at(54, b"FAT12   ") {
+		fs("vfat")
+	} else {
+		return Ok(None);
+	}))
+}
+
+/// Inspect an ISO, disk image or filesystem image
+pub fn inspect(path: &Path) -> Result<Artifact> {
+	let mut f = File::open(path)?;
+
+	let pvd = read_at(&mut f, 16 * ISO_BLOCK, ISO_BLOCK as usize)?;
+	if pvd.len() == ISO_BLOCK as usize && pvd[0] == 1 && &pvd[1..6] == b"CD001" {
+		return Ok(Artifact::Iso(inspect_iso(&mut f, &pvd)?));
+	}
+
+	if read_at(&mut f, DISK_SECTOR, 8)? == b"EFI PART" {
+		return Ok(Artifact::Disk(inspect_gpt(&mut f)?));
+	}
+
+	if let Some(fs) = detect_fs(&mut f, 0)? {
+		return Ok(Artifact::Filesystem(fs));
+	}
+
+	bail!("{} is not an ISO, GPT disk image or known filesystem image", path.display())
+}
+
+fn inspect_iso(f: &mut File, pvd: &[u8]) -> Result<IsoInfo> {
+	let volume_id = String::from_utf8_lossy(&pvd[40..72]).trim_end().to_string();
+	let md5_implanted = pvd[883..1395].windows(ISO_MD5_MARKER.len()).any(|w| w == ISO_MD5_MARKER);
+
+	// look for the El Torito boot record among the volume descriptors
+	let mut boot_entries = vec![];
+	for sector in 17..64 {
+		let vd = read_at(f, sector * ISO_BLOCK, ISO_BLOCK as usize)?;
+		if vd.len() < ISO_BLOCK as usize || vd[0] == 255 {
+			break;
+		}
+		if vd[0] == 0 && vd[7..].starts_with(b"EL TORITO SPECIFICATION") {
+			let catalog = read_at(f, u64::from(u32_le(&vd, 71)) * ISO_BLOCK, ISO_BLOCK as usize)?;
+			boot_entries = boot_catalog(&catalog);
+		}
+	}
+
+	let mut rootfs = vec![];
+	let (extent, len) = (u32_le(pvd, 156 + 2), u32_le(pvd, 156 + 10));
+	iso_find_images(f, extent, len, "", 0, &mut rootfs)?;
+
+	Ok(IsoInfo { volume_id, md5_implanted, boot_entries, rootfs })
+}
+
+fn platform_name(id: u8) -> &'static str {
+	match id {
+		0 => "BIOS",
+		1 => "PowerPC",
+		2 => "Mac",
+		0xEF => "UEFI",
+		_ => "unknown",
+	}
+}
+
+/// Parse the entries of an El Torito boot catalog
+fn boot_catalog(catalog: &[u8]) -> Vec<BootEntry> {
+	let entry = |e: &[u8], platform| BootEntry {
+		platform,
+		bootable: e[0] == 0x88,
+		sectors: u16_le(e, 6),
+		lba: u32_le(e, 8),
+	};
+	let mut entries = catalog.chunks_exact(32);
+
+	// validation entry, then the default entry for its platform
+	let (Some(validation), Some(default)) = (entries.next(), entries.next()) else {
+		return vec![];
+	};
+	if validation[0] != 1 {
+		return vec![];
+	}
+	let mut boot_entries = vec![entry(default, platform_name(validation[1]))];
+
+	// section headers: 0x90 with more to follow, 0x91 for the last one
+	while let Some(header @ [0x90 | 0x91, ..]) = entries.next() {
+		let platform = platform_name(header[1]);
+		for e in entries.by_ref().take(u16_le(header, 2).into()) {
+			boot_entries.push(entry(e, platform));
+		}
+		if header[0] == 0x91 {
+			break;
+		}
+	}
+	boot_entries
+}
+
+/// Rock Ridge name of a directory record, from its `NM` system use entry
+fn rock_ridge_name(record: &[u8], name_len: usize) -> Option<String> {
+	// the system use area follows the name, padded to an even offset
+	let mut su = &record[(33 + name_len + (name_len + 1) % 2).min(record.len())..];
+	while su.len() >= 4 {
+		let len = (su[2] as usize).min(su.len());
+		if len < 4 {
+			break;
+		}
+		if &su[..2] == b"NM" && len > 5 {
+			return Some(String::from_utf8_lossy(&su[5..len]).into_owned());
+		}
+		su = &su[len..];
+	}
+	None
+}
+
+/// Walk the ISO 9660 directory tree, collecting the files that are filesystem images
+fn iso_find_images(
+	f: &mut File, extent: u32, len: u32, prefix: &str, depth: usize,
+	found: &mut Vec<(String, Filesystem)>,
+) -> Result<()> {
+	if depth > 8 {
+		return Ok(());
+	}
+	let dir = read_at(f, u64::from(extent) * ISO_BLOCK, len as usize)?;
+	let mut pos = 0;
+	while pos < dir.len() {
+		let record_len = dir[pos] as usize;
+		if record_len == 0 {
+			// records don't cross sectors, skip the padding to the next one
+			pos = (pos / ISO_BLOCK as usize + 1) * ISO_BLOCK as usize;
+			continue;
+		}
+		let record = &dir[pos..(pos + record_len).min(dir.len())];
+		pos += record_len;
+		if record.len() < 34 {
+			continue;
+		}
+
+		let name_len = record[32] as usize;
+		let raw_name = &record[33..(33 + name_len).min(record.len())];
+		// `.` and `..`
+		if raw_name == [0] || raw_name == [1] {
+			continue;
+		}
+		let name = rock_ridge_name(record, name_len).unwrap_or_else(|| {
+			let name = String::from_utf8_lossy(raw_name);
+			name.split(';').next().unwrap_or_default().to_string()
+		});
+		let path = format!("{prefix}/{name}");
+		let (extent, size) = (u32_le(record, 2), u32_le(record, 10));
+
+		if record[25] & 2 != 0 {
+			iso_find_images(f, extent, size, &path, depth + 1, found)?;
+		} else if let Some(fs) = detect_fs(f, u64::from(extent) * ISO_BLOCK)? {
+			debug!(?path, ?fs, "Found filesystem image on ISO");
+			found.push((path, fs));
+		}
+	}
+	Ok(())
+}
+
+fn inspect_gpt(f: &mut File) -> Result<Vec<DiskPartition>> {
+	let header = read_at(f, DISK_SECTOR, 92)?;
+	if header.len() < 92 {
+		bail!("GPT header is truncated");
+	}
+	let (entries_lba, count, entry_size) =
+		(u64_le(&header, 72), u32_le(&header, 80) as usize, u32_le(&header, 84) as usize);
+	if entry_size < 128 {
+		bail!("Invalid GPT partition entry size {entry_size}");
+	}
+	if count > MAX_GPT_ENTRIES {
+		bail!("GPT header claims {count} partition entries, more than {MAX_GPT_ENTRIES}");
+	}
+	let sector = |lba: u64| {
+		lba.checked_mul(DISK_SECTOR)
+			.ok_or_else(|| color_eyre::eyre::eyre!("GPT sector {lba} is past the end of any disk"))
+	};
+
+	let table_len = count
+		.checked_mul(entry_size)
+		.ok_or_else(|| color_eyre::eyre::eyre!("GPT partition table is too large"))?;
+	let table = read_at(f, sector(entries_lba)?, table_len)?;
+	let mut partitions = vec![];
+	for (i, entry) in table.chunks_exact(entry_size).enumerate() {
+		if entry[..16].iter().all(|&b| b == 0) {
+			continue;
+		}
+		let type_uuid = uuid::Uuid::from_bytes_le(entry[..16].try_into().unwrap());
+		let (first, last) = (u64_le(entry, 32), u64_le(entry, 40));
+		let label = entry[56..128].chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]]));
+		let label = char::decode_utf16(label.take_while(|&c| c != 0))
+			.map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+			.collect();
+		partitions.push(DiskPartition {
+			index: i + 1,
+			type_uuid: type_uuid.to_string(),
+			label,
+			start: sector(first)?,
+			size: sector(last.saturating_sub(first).saturating_add(1))?,
+			filesystem: detect_fs(f, sector(first)?)?,
+		});
+	}
+	Ok(partitions)
+}
+
+impl fmt::Display for Filesystem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.compression {
+			Some(compression) => write!(f, "{} ({compression})", self.kind),
+			None => write!(f, "{}", self.kind),
+		}
+	}
+}
+
+impl fmt::Display for Artifact {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Iso(iso) => {
+				writeln!(f, "Type: ISO 9660")?;
+				writeln!(f, "Volume ID: {}", iso.volume_id)?;
+				writeln!(f, "MD5 implanted: {}", if iso.md5_implanted { "yes" } else { "no" })?;
+				writeln!(f, "El Torito boot entries:")?;
+				for e in &iso.boot_entries {
+					let bootable = if e.bootable { "" } else { " (not bootable)" };
+					writeln!(
+						f,
+						"  {} at LBA {}, {} sectors{bootable}",
+						e.platform, e.lba, e.sectors
+					)?;
+				}
+				writeln!(f, "Root filesystem images:")?;
+				for (path, fs) in &iso.rootfs {
+					writeln!(f, "  {path}: {fs}")?;
+				}
+				Ok(())
+			},
+			Self::Disk(partitions) => {
+				writeln!(f, "Type: GPT disk image")?;
+				for p in partitions {
+					let fs = p.filesystem.map_or("unknown".to_string(), |fs| fs.to_string());
+					writeln!(
+						f,
+						"  {}: {} {:?} at {}, {}, {fs}",
+						p.index,
+						p.type_uuid,
+						p.label,
+						p.start,
+						ByteSize::b(p.size).to_string_as(true)
+					)?;
+				}
+				Ok(())
+			},
+			Self::Filesystem(fs) => writeln!(f, "Type: {fs} filesystem image"),
+		}
+	}
+}
+
+#[test]
+fn test_inspect_iso() {
+	let block = |n: usize| n * ISO_BLOCK as usize;
+	let mut iso = vec![0u8; block(22)];
+
+	// primary volume descriptor, with its root directory at 19
+	let pvd = &mut iso[block(16)..block(17)];
+	pvd[0] = 1;
+	pvd[1..6].copy_from_slice(b"CD001");
+	pvd[40..72].copy_from_slice(format!("{:32}", "KATSU-TEST").as_bytes());
+	pvd[883..883 + 13].copy_from_slice(ISO_MD5_MARKER);
+	pvd[156] = 34;
+	pvd[158..162].copy_from_slice(&19u32.to_le_bytes());
+	pvd[166..170].copy_from_slice(&2048u32.to_le_bytes());
+
+	// El Torito boot record pointing at the catalog at 20, then the terminator
+	let br = &mut iso[block(17)..block(18)];
+	br[1..6].copy_from_slice(b"CD001");
+	br[7..30].copy_from_slice(b"EL TORITO SPECIFICATION");
+	br[71..75].copy_from_slice(&20u32.to_le_bytes());
+	iso[block(18)] = 255;
+
+	// root directory: `.`, `..` and SQUASHFS.IMG;1 at 21 with a Rock Ridge name
+	let dir = &mut iso[block(19)..block(20)];
+	for (i, name) in [[0u8], [1u8]].iter().enumerate() {
+		let r = &mut dir[i * 34..];
+		r[0] = 34;
+		r[25] = 2;
+		r[32] = 1;
+		r[33] = name[0];
+	}
+	let name = b"SQUASHFS.IMG;1";
+	let nm = [b"NM".as_slice(), &[17, 1, 0], b"squashfs.img"].concat();
+	let r = &mut dir[68..];
+	r[0] = (33 + name.len() + 1 + nm.len()) as u8;
+	r[2..6].copy_from_slice(&21u32.to_le_bytes());
+	r[10..14].copy_from_slice(&2048u32.to_le_bytes());
+	r[32] = name.len() as u8;
+	r[33..33 + name.len()].copy_from_slice(name);
+	r[34 + name.len()..34 + name.len() + nm.len()].copy_from_slice(&nm);
+
+	// boot catalog: BIOS default entry, then a UEFI section
+	let cat = &mut iso[block(20)..block(21)];
+	cat[0] = 1;
+	cat[32] = 0x88;
+	cat[38..40].copy_from_slice(&4u16.to_le_bytes());
+	cat[40..44].copy_from_slice(&30u32.to_le_bytes());
+	cat[64..68].copy_from_slice(&[0x91, 0xEF, 1, 0]);
+	cat[96] = 0x88;
+	cat[104..108].copy_from_slice(&31u32.to_le_bytes());
+
+	// zstd squashfs superblock
+	iso[block(21)..block(21) + 4].copy_from_slice(b"hsqs");
+	iso[block(21) + 20] = 6;
+
+	let tmp = crate::util::TestDir::new("inspect");
+	let path = tmp.join("katsu.iso");
+	std::fs::write(&path, &iso).unwrap();
+	let artifact = inspect(&path).unwrap();
+
+	assert_eq!(
+		artifact,
+		Artifact::Iso(IsoInfo {
+			volume_id: "KATSU-TEST".to_string(),
+			md5_implanted: true,
+			boot_entries: vec![
+				BootEntry { platform: "BIOS", bootable: true, lba: 30, sectors: 4 },
+				BootEntry { platform: "UEFI", bootable: true, lba: 31, sectors: 0 },
+			],
+			rootfs: vec![(
+				"/squashfs.img".to_string(),
+				Filesystem { kind: "squashfs", compression: Some("zstd") }
+			)],
+		})
+	);
+}
+
+#[test]
+fn test_inspect_gpt_corrupted() {
+	let tmp = crate::util::TestDir::new("inspect-gpt");
+	let gpt = |entries_lba: u64, count: u32, entry_size: u32| {
+		let mut disk = vec![0u8; 3 * DISK_SECTOR as usize];
+		let header = &mut disk[DISK_SECTOR as usize..];
+		header[..8].copy_from_slice(b"EFI PART");
+		header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+		header[80..84].copy_from_slice(&count.to_le_bytes());
+		header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+		let path = tmp.join("katsu.img");
+		std::fs::write(&path, disk).unwrap();
+		inspect_gpt(&mut File::open(path).unwrap())
+	};
+
+	assert!(gpt(2, 128, 128).unwrap().is_empty());
+	assert!(gpt(u64::MAX, 128, 128).is_err());
+	assert!(gpt(2, u32::MAX, 128).is_err());
+	// a table past the end of the file reads nothing instead of allocating it
+	assert!(gpt(2, 1024, u32::MAX).unwrap().is_empty());
+}
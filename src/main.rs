@@ -1,10 +1,11 @@
 mod builder;
 mod cli;
 mod config;
+mod inspect;
 mod util;
 
 use clap::Parser;
-use tracing_subscriber::{fmt, prelude::*, EnvFilter, Registry};
+use tracing_subscriber::{fmt, prelude::*, Registry};
 
 fn main() -> color_eyre::Result<()> {
 	if let Err(e) = dotenvy::dotenv() {
@@ -14,14 +15,20 @@ fn main() -> color_eyre::Result<()> {
 	}
 
 	color_eyre::install()?;
-	// default to info level logging, override with KATSU_LOG env var
+	let cli = cli::KatsuCli::parse();
 
-	let filter = EnvFilter::try_from_env("KATSU_LOG").unwrap_or_else(|_| EnvFilter::new("info"));
+	// default to info level logging, override with KATSU_LOG env var
+	let filter = util::log_filter(std::env::var("KATSU_LOG").ok().as_deref(), &cli.log_modules)?;
 	let fmtlyr = fmt::layer().pretty().with_filter(filter);
-	let subscriber = Registry::default().with(tracing_error::ErrorLayer::default()).with(fmtlyr);
+	let warnings = util::WarnCounter::default();
+	let subscriber = Registry::default()
+		.with(tracing_error::ErrorLayer::default())
+		.with(fmtlyr)
+		.with(warnings.clone());
 	tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 	tracing::trace!("カツ丼は最高！");
-	let cli = cli::KatsuCli::parse();
+	let strict = cli.fail_on_warning;
 
-	cli::parse(cli)
+	cli::parse(cli)?;
+	warnings.check(strict)
 }
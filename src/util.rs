@@ -1,5 +1,12 @@
 use color_eyre::Result;
-use std::{fs::File, path::Path};
+use std::{
+	fs::File,
+	path::Path,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Arc,
+	},
+};
 use tracing::{debug, error};
 
 #[macro_export]
@@ -181,6 +188,52 @@ macro_rules! gen_phase {
 	};
 }
 
+/// Tracing layer counting the warnings emitted, used for `--fail-on-warning`
+#[derive(Clone, Default)]
+pub struct WarnCounter(Arc<AtomicUsize>);
+
+impl WarnCounter {
+	pub fn count(&self) -> usize {
+		self.0.load(Ordering::Relaxed)
+	}
+
+	/// Fails if any warnings were emitted and `strict` is set
+	pub fn check(&self, strict: bool) -> Result<()> {
+		let count = self.count();
+		if strict && count > 0 {
+			return Err(color_eyre::eyre::eyre!(
+				"{count} warning(s) emitted during the build, failing because of --fail-on-warning"
+			));
+		}
+		Ok(())
+	}
+}
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for WarnCounter {
+	fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+		if *event.metadata().level() == tracing::Level::WARN {
+			self.0.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+}
+
+#[test]
+fn test_warn_counter() {
+	use tracing_subscriber::layer::SubscriberExt;
+
+	let counter = WarnCounter::default();
+	let subscriber = tracing_subscriber::Registry::default().with(counter.clone());
+	tracing::subscriber::with_default(subscriber, || {
+		tracing::info!("not a warning");
+		assert!(counter.check(true).is_ok());
+		tracing::warn!("something is off");
+	});
+
+	assert_eq!(counter.count(), 1);
+	assert!(counter.check(false).is_ok());
+	assert!(counter.check(true).is_err());
+}
+
 #[tracing::instrument]
 pub fn exec(cmd: &str, args: &[&str], pipe: bool) -> color_eyre::Result<Vec<u8>> {
 	tracing::debug!("Executing command");
@@ -386,6 +439,337 @@ pub fn loopdev_with_file(path: &Path) -> Result<(std::path::PathBuf, LoopDevHdl)
 	Ok((ldp, LoopDevHdl(loopdev)))
 }
 
+/// Looks up an executable by name in `$PATH`
+pub fn find_in_path(bin: &str) -> Option<std::path::PathBuf> {
+	std::env::var_os("PATH")
+		.and_then(|paths| std::env::split_paths(&paths).map(|p| p.join(bin)).find(|p| p.is_file()))
+}
+
+/// Re-executes Katsu inside a new user and mount namespace, mapped to root
+///
+/// Does nothing if we are already root (or already inside the namespace).
+/// The parent process exits with the child's status code.
+pub fn enter_userns() -> Result<()> {
+	if sudo::check() == sudo::RunningAs::Root {
+		return Ok(());
+	}
+	crate::bail_let!(Some(unshare) = find_in_path("unshare") => "`unshare` is required for --rootless");
+	let exe = std::env::current_exe()?;
+	tracing::info!("Entering user namespace");
+	let status = std::process::Command::new(unshare)
+		.args(["--user", "--map-root-user", "--mount", "--pid", "--fork", "--mount-proc", "--"])
+		.arg(exe)
+		.args(std::env::args_os().skip(1))
+		.status()?;
+	std::process::exit(status.code().unwrap_or(1));
+}
+
+/// Content-addressed cache of compressed root filesystem images
+///
+/// Enabled by setting `KATSU_ROOTIMG_CACHE` to a directory. Images are keyed by the content of the
+/// chroot and the options used to build them, so a chroot that has not changed between outputs
+/// (say, an ISO and a PXE build) is only compressed once.
+pub struct RootimgCache {
+	dir: std::path::PathBuf,
+}
+
+impl RootimgCache {
+	pub fn new(dir: impl Into<std::path::PathBuf>) -> Self {
+		Self { dir: dir.into() }
+	}
+
+	pub fn from_env() -> Option<Self> {
+		crate::env_flag!("KATSU_ROOTIMG_CACHE").map(Self::new)
+	}
+
+	/// Hashes the paths, types, permissions, ownership and contents in `chroot`, plus `opts`
+	///
+	/// Modification times are ignored. `/dev`, `/proc` and `/sys` are skipped like in the image.
+	pub fn key(chroot: &Path, opts: &str) -> Result<String> {
+		use sha2::{Digest, Sha256};
+		use std::os::unix::fs::MetadataExt;
+
+		fn walk(root: &Path, dir: &Path, hasher: &mut Sha256) -> Result<()> {
+			let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+			entries.sort_by_key(|e| e.file_name());
+			for entry in entries {
+				let path = entry.path();
+				let rel = path.strip_prefix(root)?;
+				if dir == root && ["dev", "proc", "sys"].iter().any(|d| rel == Path::new(d)) {
+					continue;
+				}
+				let meta = entry.metadata()?;
+				hasher.update(rel.as_os_str().as_encoded_bytes());
+				hasher.update(format!("\0{}:{}:{}\0", meta.mode(), meta.uid(), meta.gid()));
+				if meta.is_symlink() {
+					hasher.update(std::fs::read_link(&path)?.as_os_str().as_encoded_bytes());
+				} else if meta.is_dir() {
+					walk(root, &path, hasher)?;
+				} else if meta.is_file() {
+					std::io::copy(&mut File::open(&path)?, hasher)?;
+				}
+			}
+			Ok(())
+		}
+
+		let mut hasher = Sha256::new();
+		hasher.update(opts);
+		walk(chroot, chroot, &mut hasher)?;
+		Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+	}
+
+	/// Copies the cached image for `chroot` to `image`, or runs `build` and caches its output
+	///
+	/// Returns whether the image came from the cache.
+	pub fn get_or_build(
+		&self, chroot: &Path, opts: &str, image: &Path, build: impl FnOnce() -> Result<()>,
+	) -> Result<bool> {
+		let cached = self.dir.join(Self::key(chroot, opts)?);
+		if cached.is_file() {
+			tracing::info!(?cached, "Reusing cached root filesystem image");
+			std::fs::copy(&cached, image)?;
+			return Ok(true);
+		}
+		build()?;
+		std::fs::create_dir_all(&self.dir)?;
+		std::fs::copy(image, &cached)?;
+		Ok(false)
+	}
+}
+
+#[test]
+fn test_rootimg_cache() {
+	let tmp = TestDir::new("rootimg");
+	let cache = RootimgCache::new(tmp.join("cache"));
+	let (a, b) = (tmp.join("a"), tmp.join("b"));
+	for chroot in [&a, &b] {
+		std::fs::create_dir_all(chroot.join("etc")).unwrap();
+		std::fs::write(chroot.join("etc/os-release"), "NAME=Ultramarine\n").unwrap();
+	}
+
+	let image = tmp.join("a.img");
+	let hit =
+		cache.get_or_build(&a, "zstd", &image, || Ok(std::fs::write(&image, "squashed")?)).unwrap();
+	assert!(!hit);
+
+	// same content in another chroot, so compression is skipped
+	let image = tmp.join("b.img");
+	assert!(cache.get_or_build(&b, "zstd", &image, || unreachable!()).unwrap());
+	assert_eq!(std::fs::read_to_string(&image).unwrap(), "squashed");
+
+	// different options or content means a different image
+	assert_ne!(RootimgCache::key(&b, "zstd").unwrap(), RootimgCache::key(&b, "xz").unwrap());
+	std::fs::write(b.join("etc/hostname"), "katsu\n").unwrap();
+	assert_ne!(RootimgCache::key(&a, "zstd").unwrap(), RootimgCache::key(&b, "zstd").unwrap());
+}
+
+/// Manifest hash recorded next to an artifact (as `<artifact>.katsu-hash`), to skip rebuilding
+/// an artifact whose manifest hasn't changed
+pub struct BuildStamp {
+	path: std::path::PathBuf,
+	hash: String,
+}
+
+impl BuildStamp {
+	pub fn new(artifact: &Path, hash: String) -> Self {
+		let mut path = artifact.as_os_str().to_owned();
+		path.push(".katsu-hash");
+		Self { path: path.into(), hash }
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Whether `artifact` exists and was built from the same manifest hash
+	pub fn is_current(&self, artifact: &Path) -> bool {
+		artifact.exists()
+			&& std::fs::read_to_string(&self.path).is_ok_and(|hash| hash.trim() == self.hash)
+	}
+
+	pub fn write(&self) -> Result<()> {
+		just_write(&self.path, format!("{}\n", self.hash))
+	}
+}
+
+#[test]
+fn test_build_stamp() {
+	let tmp = TestDir::new("stamp");
+	let artifact = tmp.join("out.iso");
+	let stamp = BuildStamp::new(&artifact, "abc".to_string());
+	assert!(!stamp.is_current(&artifact));
+
+	std::fs::write(&artifact, "iso").unwrap();
+	assert!(!stamp.is_current(&artifact));
+	stamp.write().unwrap();
+	assert_eq!(std::fs::read_to_string(tmp.join("out.iso.katsu-hash")).unwrap(), "abc\n");
+	// unchanged manifest, the build is skipped
+	assert!(stamp.is_current(&artifact));
+	assert!(!BuildStamp::new(&artifact, "def".to_string()).is_current(&artifact));
+
+	std::fs::remove_file(&artifact).unwrap();
+	assert!(!stamp.is_current(&artifact));
+}
+
+/// Tracing targets of Katsu's subsystems, usable with `--max-log-level-per-module` and `KATSU_LOG`
+///
+/// Events keep the target of the module they're in, so each subsystem also lists its spans
+/// (named after the instrumented functions): everything inside them belongs to the subsystem.
+pub const LOG_MODULES: &[(&str, &[&str])] = &[
+	("builder", &[]),
+	("bootloader", &["install", "copy_liveos"]),
+	("partition", &["apply", "mount_to_chroot", "format_verity"]),
+	("dnf", &["build"]),
+	("dracut", &["dracut"]),
+];
+
+/// Builds the log filter from `KATSU_LOG` (default `info`) and `MODULE=LEVEL` overrides
+///
+/// `MODULE` is one of [`LOG_MODULES`], which map to the `katsu::<module>` target and the events
+/// inside its spans, or any other tracing target.
+pub fn log_filter(base: Option<&str>, modules: &[String]) -> Result<tracing_subscriber::EnvFilter> {
+	use tracing_subscriber::EnvFilter;
+	let mut filter = base
+		.and_then(|base| EnvFilter::try_new(base).ok())
+		.unwrap_or_else(|| EnvFilter::new("info"));
+	for module in modules {
+		crate::bail_let!(Some((target, level)) = module.split_once('=') => "Expected MODULE=LEVEL, got `{module}`");
+		let Some((_, spans)) = LOG_MODULES.iter().find(|(name, _)| *name == target) else {
+			filter = filter.add_directive(format!("{target}={level}").parse()?);
+			continue;
+		};
+		filter = filter.add_directive(format!("katsu::{target}={level}").parse()?);
+		for span in *spans {
+			filter = filter.add_directive(format!("katsu::{target}[{span}]={level}").parse()?);
+		}
+	}
+	Ok(filter)
+}
+
+#[test]
+fn test_log_filter() {
+	use tracing_subscriber::layer::SubscriberExt;
+
+	/// Counts the events that get through the filter
+	#[derive(Clone, Default)]
+	struct Events(Arc<AtomicUsize>);
+	impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Events {
+		fn on_event(&self, _: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+			self.0.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	let events = Events::default();
+	let filter = log_filter(None, &["partition=trace".to_string()]).unwrap();
+	let subscriber = tracing_subscriber::Registry::default().with(filter).with(events.clone());
+	tracing::subscriber::with_default(subscriber, || {
+		let apply = tracing::trace_span!(target: "katsu::partition", "apply");
+		assert!(!apply.is_disabled());
+		assert!(tracing::trace_span!(target: "katsu::bootloader", "install").is_disabled());
+		assert!(!tracing::info_span!(target: "katsu::bootloader", "install").is_disabled());
+
+		tracing::trace!(target: "katsu::config", "not partitioning");
+		// events inside the span keep their module's target, but belong to partitioning
+		apply.in_scope(|| tracing::trace!(target: "katsu::config", "partitioning"));
+	});
+	assert_eq!(events.0.load(Ordering::Relaxed), 1);
+
+	assert!(log_filter(None, &["partition".to_string()]).is_err());
+}
+
+/// Owner to give the output artifacts: `explicit` (`uid:gid`) if given, otherwise the user that
+/// invoked sudo, read from `SUDO_UID`/`SUDO_GID` through `env`
+pub fn output_owner(
+	explicit: Option<&str>, env: impl Fn(&str) -> Option<String>,
+) -> Result<Option<(u32, u32)>> {
+	let (uid, gid) = match explicit {
+		Some(owner) => {
+			crate::bail_let!(Some((uid, gid)) = owner.split_once(':') => "Expected UID:GID, got `{owner}`");
+			(uid.to_string(), gid.to_string())
+		},
+		None => match (env("SUDO_UID"), env("SUDO_GID")) {
+			(Some(uid), Some(gid)) => (uid, gid),
+			_ => return Ok(None),
+		},
+	};
+	Ok(Some((uid.parse()?, gid.parse()?)))
+}
+
+#[test]
+fn test_output_owner() {
+	let sudo = |var: &str| match var {
+		"SUDO_UID" => Some("1000".to_string()),
+		"SUDO_GID" => Some("1001".to_string()),
+		_ => None,
+	};
+	assert_eq!(output_owner(None, sudo).unwrap(), Some((1000, 1001)));
+	assert_eq!(output_owner(Some("42:43"), sudo).unwrap(), Some((42, 43)));
+	assert_eq!(output_owner(None, |_| None).unwrap(), None);
+	assert!(output_owner(Some("1000"), sudo).is_err());
+}
+
+/// Lists the ownership and permissions of everything in `root` as a mksquashfs pseudo file
+///
+/// Each line is `<path> m <mode> <uid> <gid>`, which `mksquashfs -pf` applies to the matching
+/// file. This lets a privileged step restore the metadata of a tree built without root.
+pub fn pseudo_file(root: &Path) -> Result<String> {
+	use std::os::unix::fs::MetadataExt;
+
+	fn quote(path: &str) -> String {
+		if path.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+			format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+		} else {
+			path.to_string()
+		}
+	}
+
+	fn walk(root: &Path, dir: &Path, out: &mut String) -> Result<()> {
+		let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+		entries.sort_by_key(|e| e.file_name());
+		for entry in entries {
+			let path = entry.path();
+			let meta = entry.metadata()?;
+			let rel = path.strip_prefix(root)?.to_string_lossy();
+			*out += &format!(
+				"{} m {:o} {} {}\n",
+				quote(&rel),
+				meta.mode() & 0o7777,
+				meta.uid(),
+				meta.gid()
+			);
+			if meta.is_dir() {
+				walk(root, &path, out)?;
+			}
+		}
+		Ok(())
+	}
+
+	let meta = std::fs::metadata(root)?;
+	let mut out = format!("/ m {:o} {} {}\n", meta.mode() & 0o7777, meta.uid(), meta.gid());
+	walk(root, root, &mut out)?;
+	Ok(out)
+}
+
+#[test]
+fn test_pseudo_file() {
+	use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+	let tmp = TestDir::new("pseudo");
+	std::fs::create_dir_all(tmp.join("home/user")).unwrap();
+	std::fs::write(tmp.join("home/user/my notes"), "").unwrap();
+	std::fs::set_permissions(tmp.join("home/user"), std::fs::Permissions::from_mode(0o700))
+		.unwrap();
+	// only works as root, otherwise the files are owned by a regular user already
+	let _ = std::os::unix::fs::chown(tmp.join("home/user"), Some(1000), Some(1000));
+
+	let user = std::fs::metadata(tmp.join("home/user")).unwrap();
+	assert_ne!(user.uid(), 0);
+	let pf = pseudo_file(&tmp).unwrap();
+	assert!(pf.starts_with("/ m "));
+	assert!(pf.contains(&format!("\nhome/user m 700 {} {}\n", user.uid(), user.gid())));
+	assert!(pf.contains("\n\"home/user/my notes\" m "));
+}
+
 pub fn just_write(path: impl AsRef<Path>, content: impl AsRef<str>) -> Result<()> {
 	use std::io::Write;
 	let (path, content) = (path.as_ref(), content.as_ref());
@@ -395,3 +779,33 @@ pub fn just_write(path: impl AsRef<Path>, content: impl AsRef<str>) -> Result<()
 	File::create(path)?.write_all(content.as_bytes())?;
 	Ok(())
 }
+
+/// Scratch directory for tests, removed on drop so a failing assert doesn't leave it behind
+#[cfg(test)]
+pub struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+	pub fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!("katsu-test-{name}-{}", std::process::id()));
+		let _ = std::fs::remove_dir_all(&path);
+		std::fs::create_dir_all(&path).unwrap();
+		Self(path)
+	}
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.0
+	}
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.0);
+	}
+}